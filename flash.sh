@@ -1,2 +1,2 @@
 source $HOME/export-esp.sh
-cargo espflash flash --release --monitor
\ No newline at end of file
+cargo espflash flash --release --monitor --partition-table partitions.csv
\ No newline at end of file
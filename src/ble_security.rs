@@ -0,0 +1,118 @@
+use alloc::format;
+use alloc::string::String;
+use core::cell::Cell;
+use critical_section::Mutex;
+use esp_hal::rng::Rng;
+use esp_println::println;
+use esp_storage::FlashStorage;
+use hmac::{Hmac, Mac};
+use rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+
+use crate::utils::get_device_secret;
+
+// Passkeys are always 6 decimal digits
+const PASSKEY_MODULO: u64 = 1_000_000;
+const PASSKEY_LABEL: &[u8] = b"pairing passkey";
+const AP_PASSWORD_LABEL: &[u8] = b"setup access point password";
+// Wrong passkeys before checking stops until the next restart,
+// a few typos are fine but guessing 6 digits isn't
+const MAX_PASSKEY_ATTEMPTS: u8 = 5;
+
+static FAILED_PASSKEY_ATTEMPTS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PasskeyCheck {
+    Valid,
+    Invalid,
+    LockedOut,
+}
+
+/// The hardware RNG is a true random source while the radio is running,
+/// which is always the case when the attribute server is up
+pub struct BleRng(pub Rng);
+
+impl RngCore for BleRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        ((self.0.random() as u64) << 32) | self.0.random() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let random_bytes = self.0.random().to_le_bytes();
+            chunk.copy_from_slice(&random_bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for BleRng {}
+
+/// Static passkey printed on the device label. It is derived from the device secret,
+/// so the backend can also show it to the owner in the app
+pub fn derive_passkey(fs: &mut FlashStorage) -> u32 {
+    let tag = derive_from_secret(fs, PASSKEY_LABEL);
+    // 64 bits brought down to 6 digits, the modulo bias is negligible
+    (u64::from_be_bytes(tag[..8].try_into().unwrap()) % PASSKEY_MODULO) as u32
+}
+
+/// WPA2 password of the setup access point, printed on the label next to the passkey.
+/// 64 bits of the HMAC as 16 hex digits, too many to guess from a captured handshake
+pub fn derive_ap_password(fs: &mut FlashStorage) -> String {
+    let tag = derive_from_secret(fs, AP_PASSWORD_LABEL);
+    tag[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// HMAC-SHA256 of a fixed label keyed with the device secret, every label
+/// gives an independent value and none of them reveals the secret
+fn derive_from_secret(fs: &mut FlashStorage, label: &[u8]) -> [u8; 32] {
+    let secret = get_device_secret(fs);
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret).unwrap();
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+/// Checks a passkey entered by the owner. Wrong tries are counted across BLE
+/// and the captive portal, once too many were made every passkey is refused
+pub fn check_passkey(fs: &mut FlashStorage, data: &[u8]) -> PasskeyCheck {
+    let failed = critical_section::with(|cs| FAILED_PASSKEY_ATTEMPTS.borrow(cs).get());
+    if failed >= MAX_PASSKEY_ATTEMPTS {
+        return PasskeyCheck::LockedOut;
+    }
+    if is_passkey_valid(fs, data) {
+        return PasskeyCheck::Valid;
+    }
+    critical_section::with(|cs| FAILED_PASSKEY_ATTEMPTS.borrow(cs).set(failed + 1));
+    println!(
+        "Wrong passkey, {} tries left",
+        MAX_PASSKEY_ATTEMPTS - failed - 1
+    );
+    PasskeyCheck::Invalid
+}
+
+/// The app sends the passkey as 6 ascii digits, padded with zeros or not
+fn is_passkey_valid(fs: &mut FlashStorage, data: &[u8]) -> bool {
+    let digits = data.iter().take_while(|byte| **byte != 0);
+    let mut passkey: u32 = 0;
+    let mut digit_count = 0;
+    for byte in digits {
+        if !byte.is_ascii_digit() || digit_count == 6 {
+            return false;
+        }
+        passkey = passkey * 10 + (byte - b'0') as u32;
+        digit_count += 1;
+    }
+    digit_count == 6 && passkey == derive_passkey(fs)
+}
@@ -0,0 +1,121 @@
+//! Maps the 0-255 brightness from the server onto output values.
+//! The curve, or a measured table, and the output range can be calibrated per board, see
+//! `utility-scripts/calibration.py` for writing them to flash.
+use embedded_storage::ReadStorage;
+use esp_println::println;
+use esp_storage::FlashStorage;
+
+use crate::{CALIBRATION_ADDR, CALIBRATION_SIZE};
+
+const CALIBRATION_MAGIC: [u8; 2] = [0xca, 0x1b];
+const HEADER_SIZE: usize = 12;
+/// Spread evenly from brightness 0 to 255, interpolated in between
+const TABLE_POINTS: usize = 17;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Curve {
+    Linear,
+    Gamma,
+    /// Lightness from CIE 1931, what people perceive as even steps
+    Cie1931,
+    /// Measured on the board, each point is a share of the output range out of 65535
+    Table([u16; TABLE_POINTS]),
+}
+
+impl Curve {
+    fn from_bytes(curve: u8, table: &[u8]) -> Option<Self> {
+        match curve {
+            0 => Some(Curve::Linear),
+            1 => Some(Curve::Gamma),
+            2 => Some(Curve::Cie1931),
+            3 => {
+                let mut points = [0u16; TABLE_POINTS];
+                for (point, bytes) in points.iter_mut().zip(table.chunks_exact(2)) {
+                    *point = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                Some(Curve::Table(points))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BrightnessMap {
+    pub curve: Curve,
+    /// Only used by `Curve::Gamma`
+    pub gamma: f32,
+    /// Output at brightness 1, anything lower doesn't light up the driver
+    pub min_output: u16,
+    pub max_output: u16,
+}
+
+impl BrightnessMap {
+    /// Brightness 0 is always fully off, no matter the calibration
+    pub fn output(&self, brightness: u8) -> u16 {
+        if brightness == 0 {
+            return 0;
+        }
+        let range = self.max_output.saturating_sub(self.min_output) as f32;
+        self.min_output + (range * self.level(brightness) + 0.5) as u16
+    }
+
+    /// Relative output between 0 and 1
+    fn level(&self, brightness: u8) -> f32 {
+        let linear = brightness as f32 / 255.0;
+        match self.curve {
+            Curve::Linear => linear,
+            Curve::Gamma => libm::powf(linear, self.gamma),
+            Curve::Cie1931 => {
+                let lightness = linear * 100.0;
+                if lightness <= 8.0 {
+                    lightness / 903.3
+                } else {
+                    let cube_root = (lightness + 16.0) / 116.0;
+                    cube_root * cube_root * cube_root
+                }
+            }
+            Curve::Table(points) => {
+                let position = linear * (TABLE_POINTS - 1) as f32;
+                // 255 is exactly the last point, the segment before it gets it
+                let index = (position as usize).min(TABLE_POINTS - 2);
+                let fraction = position - index as f32;
+                let start = points[index] as f32;
+                let end = points[index + 1] as f32;
+                (start + (end - start) * fraction) / u16::MAX as f32
+            }
+        }
+    }
+
+    /// Record layout: magic (2), curve (1), padding (1), gamma as f32 (4), min and max output as u16 (2 + 2),
+    /// then the table points as u16 (2 each) which only the table curve reads, all little endian
+    fn from_bytes(bytes: &[u8; CALIBRATION_SIZE as usize]) -> Option<Self> {
+        if bytes[0..2] != CALIBRATION_MAGIC {
+            return None;
+        }
+        let map = BrightnessMap {
+            curve: Curve::from_bytes(bytes[2], &bytes[HEADER_SIZE..])?,
+            gamma: f32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            min_output: u16::from_le_bytes([bytes[8], bytes[9]]),
+            max_output: u16::from_le_bytes([bytes[10], bytes[11]]),
+        };
+        if map.min_output > map.max_output || map.gamma.is_nan() || map.gamma <= 0.0 {
+            return None;
+        }
+        Some(map)
+    }
+}
+
+/// Board calibration from flash, `default` is used when there is none
+pub fn load_calibration(fs: &mut FlashStorage, default: BrightnessMap) -> BrightnessMap {
+    let mut bytes = [0xffu8; CALIBRATION_SIZE as usize];
+    fs.read(CALIBRATION_ADDR, &mut bytes).unwrap();
+    if bytes == [0xff; CALIBRATION_SIZE as usize] {
+        return default;
+    }
+    let calibration = BrightnessMap::from_bytes(&bytes);
+    if calibration.is_none() {
+        println!("Invalid brightness calibration, using defaults");
+    }
+    calibration.unwrap_or(default)
+}
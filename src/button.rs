@@ -0,0 +1,155 @@
+//! Debounced reset button on GPIO4, sampled from the ticker interrupt.
+//! Gestures are turned into events that the main loop picks up.
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use critical_section::{CriticalSection, Mutex};
+use esp_hal::gpio::Input;
+
+const DEBOUNCE_MS: u64 = 50;
+// Anything held longer is not a short press, but not a long one either
+const SHORT_PRESS_MAX_MS: u64 = 1000;
+// A short press is only reported once this passed without a second one
+const DOUBLE_PRESS_GAP_MS: u64 = 400;
+const LONG_PRESS_MS: u64 = 5 * 1000;
+const FACTORY_RESET_PRESS_MS: u64 = 15 * 1000;
+
+const NO_EVENT: u8 = 0;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ButtonEvent {
+    /// Toggles the light
+    ShortPress = 1,
+    /// Opens the pairing window again
+    LongPress = 2,
+    /// Sent while the button is still held, so the user knows when to let go
+    FactoryReset = 3,
+    /// Cycles through the scenes
+    DoublePress = 4,
+}
+
+impl ButtonEvent {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(ButtonEvent::ShortPress),
+            2 => Some(ButtonEvent::LongPress),
+            3 => Some(ButtonEvent::FactoryReset),
+            4 => Some(ButtonEvent::DoublePress),
+            _ => None,
+        }
+    }
+}
+
+struct Button {
+    input: Input<'static>,
+    is_pressed: bool,
+    // When the raw level started to differ from the debounced one
+    bouncing_since: Option<u64>,
+    pressed_since: u64,
+    factory_reset_sent: bool,
+    // Release of a short press that might still become a double press
+    short_press_at: Option<u64>,
+}
+
+static BUTTON: Mutex<RefCell<Option<Button>>> = Mutex::new(RefCell::new(None));
+// Only the latest gesture is kept, the main loop isn't expected to fall behind by more
+static EVENT: AtomicU8 = AtomicU8::new(NO_EVENT);
+
+pub fn init(input: Input<'static>) {
+    let is_pressed = input.is_high();
+    critical_section::with(|cs| {
+        BUTTON.borrow_ref_mut(cs).replace(Button {
+            input,
+            is_pressed,
+            bouncing_since: None,
+            pressed_since: 0,
+            // A press that started before boot shouldn't count as a gesture
+            factory_reset_sent: is_pressed,
+            short_press_at: None,
+        })
+    });
+}
+
+/// Debounced button level, Improv uses it to authorize provisioning
+#[cfg(feature = "improv")]
+pub fn is_pressed() -> bool {
+    critical_section::with(|cs| {
+        BUTTON
+            .borrow_ref(cs)
+            .as_ref()
+            .is_some_and(|button| button.is_pressed)
+    })
+}
+
+pub fn take_event() -> Option<ButtonEvent> {
+    ButtonEvent::from_u8(EVENT.swap(NO_EVENT, Ordering::AcqRel))
+}
+
+/// Drops gestures made while nobody was listening, e.g. during pairing
+pub fn clear_events() {
+    EVENT.store(NO_EVENT, Ordering::Release);
+}
+
+pub(crate) fn tick(cs: CriticalSection, now: u64) {
+    let mut button = BUTTON.borrow_ref_mut(cs);
+    let Some(button) = button.as_mut() else {
+        return;
+    };
+    if let Some(event) = button.update(now) {
+        EVENT.store(event as u8, Ordering::Release);
+    }
+}
+
+impl Button {
+    fn update(&mut self, now: u64) -> Option<ButtonEvent> {
+        if self.input.is_high() == self.is_pressed {
+            self.bouncing_since = None;
+        } else {
+            let bouncing_since = *self.bouncing_since.get_or_insert(now);
+            if now - bouncing_since >= DEBOUNCE_MS {
+                self.bouncing_since = None;
+                self.is_pressed = !self.is_pressed;
+                return self.on_edge(now);
+            }
+        }
+        if let Some(short_press_at) = self.short_press_at {
+            if !self.is_pressed && now - short_press_at > DOUBLE_PRESS_GAP_MS {
+                self.short_press_at = None;
+                return Some(ButtonEvent::ShortPress);
+            }
+        }
+        if self.is_pressed
+            && !self.factory_reset_sent
+            && now - self.pressed_since >= FACTORY_RESET_PRESS_MS
+        {
+            self.factory_reset_sent = true;
+            return Some(ButtonEvent::FactoryReset);
+        }
+        None
+    }
+
+    fn on_edge(&mut self, now: u64) -> Option<ButtonEvent> {
+        if self.is_pressed {
+            self.pressed_since = now;
+            self.factory_reset_sent = false;
+            return None;
+        }
+        if self.factory_reset_sent {
+            return None;
+        }
+        let held_ms = now - self.pressed_since;
+        if held_ms >= LONG_PRESS_MS {
+            self.short_press_at = None;
+            Some(ButtonEvent::LongPress)
+        } else if held_ms <= SHORT_PRESS_MAX_MS {
+            if self.short_press_at.take().is_some() {
+                return Some(ButtonEvent::DoublePress);
+            }
+            self.short_press_at = Some(now);
+            None
+        } else {
+            self.short_press_at = None;
+            None
+        }
+    }
+}
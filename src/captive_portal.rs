@@ -0,0 +1,474 @@
+//! Fallback provisioning for phones and laptops without BLE. The device opens
+//! an access point, hands out addresses with a tiny DHCP server, answers every
+//! DNS query with its own address and serves the settings form over HTTP.
+use core::str;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use blocking_network_stack::{ipv4, Socket, Stack, UdpSocket};
+use embedded_io::{Read, Write};
+use esp_println::println;
+use esp_storage::FlashStorage;
+use esp_wifi::wifi::{
+    AccessPointConfiguration, AuthMethod, Configuration, WifiController, WifiDevice,
+};
+use smoltcp::socket::udp::PacketMetadata;
+use smoltcp::wire::{IpAddress, Ipv4Address};
+
+use crate::ble_security::{check_passkey, derive_ap_password, PasskeyCheck};
+use crate::coap::CoapClient;
+use crate::pairing::PairingIdentity;
+use crate::status_led::{self, Status};
+use crate::utils::{get_device_secret, now, set_device_configured, store_server_config};
+use crate::wifi_utils::{connect_to_wifi, store_wifi_credentials};
+
+const AP_IP: [u8; 4] = [192, 168, 4, 1];
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DNS_PORT: u16 = 53;
+const HTTP_PORT: u16 = 80;
+const HTTP_READ_TIMEOUT_MS: u64 = 5 * 1000;
+const LEASE_TIME_S: u32 = 60 * 60;
+// Addresses handed out are AP_IP with the last byte from this range
+const FIRST_LEASE: u8 = 2;
+const MAX_LEASES: usize = 8;
+
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTIONS_OFFSET: usize = 240;
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+
+pub struct PortalBuffers {
+    dhcp_rx_buffer: [u8; 1024],
+    dhcp_tx_buffer: [u8; 1024],
+    dhcp_rx_meta: [PacketMetadata; 4],
+    dhcp_tx_meta: [PacketMetadata; 4],
+    dns_rx_buffer: [u8; 512],
+    dns_tx_buffer: [u8; 512],
+    dns_rx_meta: [PacketMetadata; 4],
+    dns_tx_meta: [PacketMetadata; 4],
+    http_rx_buffer: [u8; 1536],
+    http_tx_buffer: [u8; 4096],
+}
+
+impl PortalBuffers {
+    pub fn new() -> Self {
+        Self {
+            dhcp_rx_buffer: [0u8; 1024],
+            dhcp_tx_buffer: [0u8; 1024],
+            dhcp_rx_meta: [PacketMetadata::EMPTY; 4],
+            dhcp_tx_meta: [PacketMetadata::EMPTY; 4],
+            dns_rx_buffer: [0u8; 512],
+            dns_tx_buffer: [0u8; 512],
+            dns_rx_meta: [PacketMetadata::EMPTY; 4],
+            dns_tx_meta: [PacketMetadata::EMPTY; 4],
+            http_rx_buffer: [0u8; 1536],
+            http_tx_buffer: [0u8; 4096],
+        }
+    }
+}
+
+impl Default for PortalBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The AP interface has a fixed address, we are the DHCP server on it
+pub fn configure_ap_stack(stack: &mut Stack<WifiDevice>) {
+    stack
+        .set_iface_configuration(&ipv4::Configuration::Client(
+            ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+                ip: ipv4::Ipv4Addr::from(AP_IP),
+                subnet: ipv4::Subnet {
+                    gateway: ipv4::Ipv4Addr::from(AP_IP),
+                    mask: ipv4::Mask(24),
+                },
+                dns: None,
+                secondary_dns: None,
+            }),
+        ))
+        .unwrap();
+}
+
+pub struct CaptivePortal<'s, 'n> {
+    dhcp_socket: UdpSocket<'s, 'n, WifiDevice<'n>>,
+    dns_socket: UdpSocket<'s, 'n, WifiDevice<'n>>,
+    http_socket: Socket<'s, 'n, WifiDevice<'n>>,
+    // Client hardware addresses, the index is the offset from FIRST_LEASE
+    leases: Vec<[u8; 6]>,
+    last_error: Option<String>,
+}
+
+impl<'s, 'n> CaptivePortal<'s, 'n> {
+    pub fn new(stack: &'s Stack<'n, WifiDevice<'n>>, buffers: &'n mut PortalBuffers) -> Self {
+        let mut dhcp_socket = stack.get_udp_socket(
+            &mut buffers.dhcp_rx_meta,
+            &mut buffers.dhcp_rx_buffer,
+            &mut buffers.dhcp_tx_meta,
+            &mut buffers.dhcp_tx_buffer,
+        );
+        dhcp_socket.bind(DHCP_SERVER_PORT).unwrap();
+        let mut dns_socket = stack.get_udp_socket(
+            &mut buffers.dns_rx_meta,
+            &mut buffers.dns_rx_buffer,
+            &mut buffers.dns_tx_meta,
+            &mut buffers.dns_tx_buffer,
+        );
+        dns_socket.bind(DNS_PORT).unwrap();
+        let http_socket =
+            stack.get_socket(&mut buffers.http_rx_buffer, &mut buffers.http_tx_buffer);
+        Self {
+            dhcp_socket,
+            dns_socket,
+            http_socket,
+            leases: vec![],
+            last_error: None,
+        }
+    }
+
+    /// Runs the access point until the form was submitted with working credentials
+    /// or the timeout passes. Returns true when the device got configured
+    pub fn run(
+        &mut self,
+        controller: &mut WifiController,
+        sta_stack: &Stack<WifiDevice>,
+        coap_client: &mut CoapClient,
+        identity: &PairingIdentity,
+        timeout_ms: u64,
+    ) -> bool {
+        let ssid = format!("{} setup", identity.name);
+        start_access_point(controller, &ssid);
+        status_led::set(Status::Pairing);
+        println!("Captive portal started on {}", ssid);
+        let deadline = now() + timeout_ms;
+        loop {
+            self.handle_dhcp();
+            self.handle_dns();
+            if let Some(form) = self.handle_http(&ssid) {
+                if self.apply_settings(&form, controller, sta_stack, coap_client, identity) {
+                    return true;
+                }
+                // Connecting switched the radio to station mode
+                start_access_point(controller, &ssid);
+                status_led::set(Status::Pairing);
+            }
+            if now() > deadline {
+                println!("Captive portal timed out");
+                controller.stop().unwrap();
+                return false;
+            }
+        }
+    }
+
+    fn apply_settings(
+        &mut self,
+        form: &str,
+        controller: &mut WifiController,
+        sta_stack: &Stack<WifiDevice>,
+        coap_client: &mut CoapClient,
+        identity: &PairingIdentity,
+    ) -> bool {
+        let mut fs = FlashStorage::new();
+        let passkey = form_value(form, "passkey").unwrap_or_default();
+        match check_passkey(&mut fs, passkey.as_bytes()) {
+            PasskeyCheck::Valid => {}
+            PasskeyCheck::Invalid => {
+                self.last_error = Some(String::from("Wrong passkey"));
+                return false;
+            }
+            PasskeyCheck::LockedOut => {
+                self.last_error = Some(String::from(
+                    "Too many wrong passkeys, restart the light to try again",
+                ));
+                return false;
+            }
+        }
+        let ssid = form_value(form, "ssid").unwrap_or_default();
+        let password = form_value(form, "password").unwrap_or_default();
+        if ssid.is_empty() || !store_wifi_credentials(&mut fs, ssid.as_bytes(), password.as_bytes())
+        {
+            self.last_error = Some(String::from("Invalid network name or password"));
+            return false;
+        }
+        let server_ip = form_value(form, "server_ip").unwrap_or_default();
+        let server_port = form_value(form, "server_port").unwrap_or_default();
+        // Empty server fields keep the address the firmware was built with
+        if !server_ip.is_empty() || !server_port.is_empty() {
+            match (server_ip.parse::<Ipv4Address>(), server_port.parse::<u16>()) {
+                (Ok(ip), Ok(port)) => {
+                    if let Err(err) = store_server_config(&mut fs, IpAddress::Ipv4(ip), port) {
+                        println!("{}", err);
+                        self.last_error = Some(String::from("Could not save the server address"));
+                        return false;
+                    }
+                    coap_client.set_server(IpAddress::Ipv4(ip), port);
+                }
+                _ => {
+                    self.last_error = Some(String::from("Invalid server address"));
+                    return false;
+                }
+            }
+        }
+        controller.stop().unwrap();
+        if let Err(err) = connect_to_wifi(controller, sta_stack) {
+            self.last_error = Some(format!("{}", err));
+            return false;
+        }
+        if let Err(err) =
+            coap_client.verify_device(&identity.resource_path, &get_device_secret(&mut fs))
+        {
+            self.last_error = Some(format!("{}", err));
+            return false;
+        }
+        set_device_configured(&mut fs);
+        true
+    }
+
+    fn handle_dhcp(&mut self) {
+        let mut buf = [0u8; 576];
+        let Ok((len, _, _)) = self.dhcp_socket.receive(&mut buf) else {
+            return;
+        };
+        let request = &buf[..len];
+        if len <= DHCP_OPTIONS_OFFSET || request[0] != 1 || request[236..240] != DHCP_MAGIC_COOKIE {
+            return;
+        }
+        let reply_type = match dhcp_message_type(request) {
+            Some(DHCP_DISCOVER) => DHCP_OFFER,
+            Some(DHCP_REQUEST) => DHCP_ACK,
+            _ => return,
+        };
+        let mut client_mac = [0u8; 6];
+        client_mac.copy_from_slice(&request[28..34]);
+        let Some(lease) = self.lease_for(client_mac) else {
+            println!("No free DHCP leases");
+            return;
+        };
+        let reply = build_dhcp_reply(request, reply_type, lease);
+        let broadcast = IpAddress::Ipv4(Ipv4Address::BROADCAST);
+        let _ = self.dhcp_socket.send(broadcast, DHCP_CLIENT_PORT, &reply);
+    }
+
+    fn lease_for(&mut self, client_mac: [u8; 6]) -> Option<[u8; 4]> {
+        let index = match self.leases.iter().position(|mac| *mac == client_mac) {
+            Some(index) => index,
+            None => {
+                if self.leases.len() == MAX_LEASES {
+                    return None;
+                }
+                self.leases.push(client_mac);
+                self.leases.len() - 1
+            }
+        };
+        Some([AP_IP[0], AP_IP[1], AP_IP[2], FIRST_LEASE + index as u8])
+    }
+
+    /// Every name resolves to us, that's what makes operating systems show the portal
+    fn handle_dns(&mut self) {
+        let mut buf = [0u8; 512];
+        let Ok((len, address, port)) = self.dns_socket.receive(&mut buf) else {
+            return;
+        };
+        if let Some(response) = build_dns_response(&buf[..len]) {
+            let _ = self.dns_socket.send(address, port, &response);
+        }
+    }
+
+    /// Serves the form, returns the submitted urlencoded body when it gets posted
+    fn handle_http(&mut self, title: &str) -> Option<String> {
+        self.http_socket.work();
+        if !self.http_socket.is_open() {
+            self.http_socket.listen(HTTP_PORT).unwrap();
+        }
+        if !self.http_socket.is_connected() {
+            return None;
+        }
+        let request = self.read_http_request();
+        let form = match &request {
+            Some(request) if request.starts_with("POST /save") => request
+                .split_once("\r\n\r\n")
+                .map(|(_, body)| String::from(body)),
+            _ => None,
+        };
+        let page = match (&request, &form) {
+            (None, _) => None,
+            (Some(_), Some(_)) => Some(saved_page()),
+            (Some(_), None) => Some(form_page(title, self.last_error.as_deref())),
+        };
+        if let Some(page) = page {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                page.len(),
+                page
+            );
+            let _ = self.http_socket.write_all(response.as_bytes());
+            let _ = self.http_socket.flush();
+        }
+        self.http_socket.close();
+        form
+    }
+
+    fn read_http_request(&mut self) -> Option<String> {
+        let wait_end = now() + HTTP_READ_TIMEOUT_MS;
+        let mut buffer = [0u8; 1024];
+        let mut pos = 0;
+        while let Ok(len) = self.http_socket.read(&mut buffer[pos..]) {
+            pos += len;
+            let request = str::from_utf8(&buffer[..pos]).ok()?;
+            if let Some((headers, body)) = request.split_once("\r\n\r\n") {
+                if body.len() >= content_length(headers) {
+                    return Some(String::from(request));
+                }
+            }
+            if pos == buffer.len() || now() > wait_end {
+                return None;
+            }
+        }
+        None
+    }
+}
+
+fn start_access_point(controller: &mut WifiController, ssid: &str) {
+    let mut fs = FlashStorage::new();
+    // Keeps the form and the passkey sent with it off the air for anyone without the password
+    let ap_config = Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ssid.into(),
+        auth_method: AuthMethod::WPA2Personal,
+        password: derive_ap_password(&mut fs),
+        ..Default::default()
+    });
+    controller.set_configuration(&ap_config).unwrap();
+    controller.start().unwrap();
+}
+
+fn dhcp_message_type(request: &[u8]) -> Option<u8> {
+    let mut options = &request[DHCP_OPTIONS_OFFSET..];
+    while options.len() >= 2 {
+        match options[0] {
+            // Padding
+            0 => options = &options[1..],
+            // End
+            255 => return None,
+            // Message type is a single byte
+            53 if options[1] == 1 => return options.get(2).copied(),
+            53 => return None,
+            _ => {
+                let option_len = options[1] as usize + 2;
+                options = options.get(option_len..)?;
+            }
+        }
+    }
+    None
+}
+
+fn build_dhcp_reply(request: &[u8], reply_type: u8, lease: [u8; 4]) -> Vec<u8> {
+    let mut reply = vec![0u8; DHCP_OPTIONS_OFFSET];
+    // Boot reply, ethernet, hardware address length
+    reply[0..3].copy_from_slice(&[2, 1, 6]);
+    // Transaction id, secs and flags are echoed back
+    reply[4..12].copy_from_slice(&request[4..12]);
+    reply[16..20].copy_from_slice(&lease);
+    reply[20..24].copy_from_slice(&AP_IP);
+    reply[28..44].copy_from_slice(&request[28..44]);
+    reply[236..240].copy_from_slice(&DHCP_MAGIC_COOKIE);
+    reply.extend_from_slice(&[53, 1, reply_type]);
+    reply.extend_from_slice(&[54, 4]);
+    reply.extend_from_slice(&AP_IP);
+    reply.extend_from_slice(&[51, 4]);
+    reply.extend_from_slice(&LEASE_TIME_S.to_be_bytes());
+    reply.extend_from_slice(&[1, 4, 255, 255, 255, 0]);
+    reply.extend_from_slice(&[3, 4]);
+    reply.extend_from_slice(&AP_IP);
+    reply.extend_from_slice(&[6, 4]);
+    reply.extend_from_slice(&AP_IP);
+    reply.push(255);
+    reply
+}
+
+fn build_dns_response(query: &[u8]) -> Option<Vec<u8>> {
+    // Header is 12 bytes, we only answer standard queries with one question
+    if query.len() < 12 || query[2] & 0x80 != 0 || query[4..6] != [0, 1] {
+        return None;
+    }
+    let mut question_end = 12;
+    while *query.get(question_end)? != 0 {
+        question_end += query[question_end] as usize + 1;
+    }
+    // Terminating zero, type and class
+    question_end += 5;
+    let question = query.get(12..question_end)?;
+    let mut response = Vec::with_capacity(question_end + 16);
+    response.extend_from_slice(&query[0..2]);
+    // Standard response, recursion available, one question and one answer
+    response.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+    response.extend_from_slice(question);
+    // Pointer to the name in the question, type A, class IN, 60 s ttl, 4 bytes
+    response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+    response.extend_from_slice(&AP_IP);
+    Some(response)
+}
+
+fn content_length(headers: &str) -> usize {
+    headers
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn form_value(form: &str, key: &str) -> Option<String> {
+    form.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| url_decode(value))
+}
+
+fn url_decode(value: &str) -> String {
+    let mut decoded: Vec<u8> = vec![];
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = [bytes.next().unwrap_or(b'0'), bytes.next().unwrap_or(b'0')];
+                let hex = str::from_utf8(&hex).unwrap_or("00");
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or(b'?'));
+            }
+            _ => decoded.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn form_page(title: &str, error: Option<&str>) -> String {
+    let error = match error {
+        Some(error) => format!("<p style=\"color:red\">{}</p>", error),
+        None => String::new(),
+    };
+    format!(
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+        <title>{}</title></head><body><h1>{}</h1>{}\
+        <form method=\"post\" action=\"/save\">\
+        <p>Wi-Fi name<br><input name=\"ssid\" maxlength=\"32\" required></p>\
+        <p>Wi-Fi password<br><input name=\"password\" type=\"password\" maxlength=\"64\"></p>\
+        <p>Server address (optional)<br><input name=\"server_ip\"></p>\
+        <p>Server port (optional)<br><input name=\"server_port\" type=\"number\"></p>\
+        <p>Passkey from the label<br><input name=\"passkey\" inputmode=\"numeric\" maxlength=\"6\" required></p>\
+        <p><input type=\"submit\" value=\"Save\"></p></form></body></html>",
+        title, title, error
+    )
+}
+
+fn saved_page() -> String {
+    String::from(
+        "<!DOCTYPE html><html><body><h1>Connecting</h1>\
+        <p>This network disappears once the device is connected. \
+        If it comes back, open this page again to see what went wrong.</p></body></html>",
+    )
+}
@@ -0,0 +1,355 @@
+//! Wall clock, `utils::now` only counts from boot.
+//! Set by SNTP, or roughly by the server until that got through.
+use core::cell::Cell;
+use core::fmt;
+
+use critical_section::Mutex;
+
+use crate::utils::now;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+// Syncs closer together than this say more about network delay than about drift
+const MIN_DRIFT_INTERVAL_MS: u64 = 10 * 60 * 1000;
+// The crystal is good for a lot less, anything above is a bad measurement
+const MAX_DRIFT_PPM: i64 = 500;
+
+#[derive(Copy, Clone)]
+struct WallClock {
+    unix_ms: u64,
+    // `now()` when `unix_ms` was taken
+    synced_at: u64,
+    // Only SNTP is precise enough to measure drift with
+    is_precise: bool,
+    // How much faster than real time the local clock runs, `None` until measured
+    drift_ppm: Option<i64>,
+}
+
+impl WallClock {
+    fn unix_ms(&self, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.synced_at) as i64;
+        let corrected = elapsed - elapsed * self.drift_ppm.unwrap_or(0) / 1_000_000;
+        self.unix_ms.saturating_add_signed(corrected)
+    }
+}
+
+static CLOCK: Mutex<Cell<Option<WallClock>>> = Mutex::new(Cell::new(None));
+static TIME_ZONE: Mutex<Cell<Option<TimeZone>>> = Mutex::new(Cell::new(None));
+
+/// Rough time from the server, ignored once SNTP got through
+#[cfg_attr(not(feature = "light"), allow(dead_code))]
+pub fn set_unix_time(unix_secs: u64) {
+    let synced_at = now();
+    critical_section::with(|cs| {
+        let clock = CLOCK.borrow(cs);
+        if clock.get().is_some_and(|clock| clock.is_precise) {
+            return;
+        }
+        clock.set(Some(WallClock {
+            unix_ms: unix_secs * 1000,
+            synced_at,
+            is_precise: false,
+            drift_ppm: None,
+        }));
+    });
+}
+
+/// Time measured by SNTP at `synced_at`, also used to learn how much the local clock drifts
+pub fn sync(unix_ms: u64, synced_at: u64) {
+    critical_section::with(|cs| {
+        let clock = CLOCK.borrow(cs);
+        let drift_ppm = match clock.get() {
+            Some(previous) if previous.is_precise => {
+                let elapsed = synced_at.saturating_sub(previous.synced_at) as i64;
+                let actual = unix_ms as i64 - previous.unix_ms as i64;
+                if elapsed < MIN_DRIFT_INTERVAL_MS as i64 {
+                    previous.drift_ppm
+                } else {
+                    let measured = (elapsed - actual) * 1_000_000 / elapsed;
+                    match previous.drift_ppm {
+                        _ if measured.abs() > MAX_DRIFT_PPM => previous.drift_ppm,
+                        // Averaged, a single sync can be off by the network delay
+                        Some(drift_ppm) => Some((drift_ppm + measured) / 2),
+                        None => Some(measured),
+                    }
+                }
+            }
+            _ => None,
+        };
+        clock.set(Some(WallClock {
+            unix_ms,
+            synced_at,
+            is_precise: true,
+            drift_ppm,
+        }));
+    });
+}
+
+/// Milliseconds since the unix epoch, `None` until the time was set
+pub fn unix_time_ms() -> Option<u64> {
+    let now = now();
+    critical_section::with(|cs| CLOCK.borrow(cs).get()).map(|clock| clock.unix_ms(now))
+}
+
+/// Seconds since the unix epoch, `None` until the time was set
+pub fn unix_time() -> Option<u64> {
+    unix_time_ms().map(|unix_ms| unix_ms / 1000)
+}
+
+pub fn set_time_zone(time_zone: TimeZone) {
+    critical_section::with(|cs| TIME_ZONE.borrow(cs).set(Some(time_zone)));
+}
+
+/// Seconds local time is ahead of UTC at `unix_secs`, `None` without a configured time zone
+pub fn utc_offset_at(unix_secs: i64) -> Option<i64> {
+    critical_section::with(|cs| TIME_ZONE.borrow(cs).get())
+        .map(|time_zone| time_zone.offset_at(unix_secs))
+}
+
+/// Local time, UTC without a configured time zone
+pub fn local_time() -> Option<LocalTime> {
+    let unix_secs = unix_time()? as i64;
+    let local_secs = unix_secs + utc_offset_at(unix_secs).unwrap_or(0);
+    let (year, month, day) = civil_from_days(local_secs.div_euclid(SECS_PER_DAY));
+    let secs_of_day = local_secs.rem_euclid(SECS_PER_DAY);
+    Some(LocalTime {
+        year,
+        month,
+        day,
+        hour: (secs_of_day / 3600) as u8,
+        minute: (secs_of_day / 60 % 60) as u8,
+        second: (secs_of_day % 60) as u8,
+    })
+}
+
+pub struct LocalTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for LocalTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TimeZone {
+    // Added to UTC, so east of Greenwich is positive, unlike in the rule
+    std_offset_secs: i64,
+    dst: Option<Dst>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Dst {
+    offset_secs: i64,
+    start: Transition,
+    end: Transition,
+}
+
+/// `Mm.w.d/time`, the `w`th weekday `d` (0 is Sunday) of month `m`, week 5 meaning the last one
+#[derive(Copy, Clone, Debug)]
+struct Transition {
+    month: u8,
+    week: u8,
+    weekday: u8,
+    time_secs: i64,
+}
+
+impl TimeZone {
+    /// Parses a POSIX TZ rule like `CET-1CEST,M3.5.0,M10.5.0/3`.
+    /// Only the `M` form of DST changes is understood, it is what current zones use.
+    pub fn parse(rule: &str) -> Option<Self> {
+        let mut parser = Parser { rest: rule };
+        parser.name()?;
+        let std_offset_secs = -parser.duration()?;
+        if parser.rest.is_empty() {
+            return Some(Self {
+                std_offset_secs,
+                dst: None,
+            });
+        }
+        parser.name()?;
+        // DST is an hour ahead unless the rule says otherwise
+        let offset_secs = if parser.rest.starts_with(',') {
+            std_offset_secs + 3600
+        } else {
+            -parser.duration()?
+        };
+        parser.expect(',')?;
+        let start = parser.transition()?;
+        parser.expect(',')?;
+        let end = parser.transition()?;
+        if !parser.rest.is_empty() {
+            return None;
+        }
+        Some(Self {
+            std_offset_secs,
+            dst: Some(Dst {
+                offset_secs,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// Seconds local time is ahead of UTC at `unix_secs`
+    pub fn offset_at(&self, unix_secs: i64) -> i64 {
+        let Some(dst) = self.dst else {
+            return self.std_offset_secs;
+        };
+        let (year, _, _) =
+            civil_from_days((unix_secs + self.std_offset_secs).div_euclid(SECS_PER_DAY));
+        // Each change happens at the local time in effect before it
+        let start = dst.start.local_secs(year) - self.std_offset_secs;
+        let end = dst.end.local_secs(year) - dst.offset_secs;
+        let is_dst = if start < end {
+            start <= unix_secs && unix_secs < end
+        } else {
+            // Southern hemisphere, DST spans the new year
+            unix_secs < end || start <= unix_secs
+        };
+        if is_dst {
+            dst.offset_secs
+        } else {
+            self.std_offset_secs
+        }
+    }
+}
+
+impl Transition {
+    /// Local seconds since the epoch when the change happens in `year`
+    fn local_secs(&self, year: i64) -> i64 {
+        let first_day = days_from_civil(year, self.month, 1);
+        // 1970-01-01 was a Thursday
+        let first_weekday = (first_day + 4).rem_euclid(7);
+        let mut day = first_day
+            + (self.weekday as i64 - first_weekday).rem_euclid(7)
+            + (self.week as i64 - 1) * 7;
+        let next_month = match self.month {
+            12 => days_from_civil(year + 1, 1, 1),
+            month => days_from_civil(year, month + 1, 1),
+        };
+        // Week 5 is the last one, which can be the fourth
+        while day >= next_month {
+            day -= 7;
+        }
+        day * SECS_PER_DAY + self.time_secs
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    /// Zone abbreviation, letters or anything in angle brackets like `<+0530>`
+    fn name(&mut self) -> Option<()> {
+        let len = match self.rest.strip_prefix('<') {
+            Some(quoted) => quoted.find('>')? + 2,
+            None => self
+                .rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.rest.len()),
+        };
+        if len < 3 {
+            return None;
+        }
+        self.rest = &self.rest[len..];
+        Some(())
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds
+    fn duration(&mut self) -> Option<i64> {
+        let sign = if self.expect('-').is_some() {
+            -1
+        } else {
+            let _ = self.expect('+');
+            1
+        };
+        let mut secs = self.number()? * 3600;
+        for unit_secs in [60, 1] {
+            if self.expect(':').is_none() {
+                break;
+            }
+            secs += self.number()? * unit_secs;
+        }
+        Some(sign * secs)
+    }
+
+    fn transition(&mut self) -> Option<Transition> {
+        self.expect('M')?;
+        let month = self.number()?;
+        self.expect('.')?;
+        let week = self.number()?;
+        self.expect('.')?;
+        let weekday = self.number()?;
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || !(0..=6).contains(&weekday) {
+            return None;
+        }
+        // 02:00 when left out
+        let time_secs = match self.expect('/') {
+            Some(()) => self.duration()?,
+            None => 2 * 3600,
+        };
+        Some(Transition {
+            month: month as u8,
+            week: week as u8,
+            weekday: weekday as u8,
+            time_secs,
+        })
+    }
+
+    fn number(&mut self) -> Option<i64> {
+        let len = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let number = self.rest[..len].parse().ok()?;
+        self.rest = &self.rest[len..];
+        Some(number)
+    }
+
+    fn expect(&mut self, c: char) -> Option<()> {
+        self.rest = self.rest.strip_prefix(c)?;
+        Some(())
+    }
+}
+
+/// Year, month and day of a day since the epoch, from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Counted from March, so the leap day comes last
+    let march_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * march_month + 2) / 5 + 1) as u8;
+    let month = if march_month < 10 {
+        march_month + 3
+    } else {
+        march_month - 9
+    } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let march_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * march_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
@@ -1,13 +1,21 @@
+use alloc::format;
+use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::{string::ToString, vec};
 use anyhow::{anyhow, Error};
 use blocking_network_stack::UdpSocket;
-use coap_lite::{CoapOption, ContentFormat, MessageClass, MessageType, Packet, RequestType};
+use coap_lite::{
+    CoapOption, ContentFormat, MessageClass, MessageType, Packet, RequestType, ResponseType,
+};
+use esp_hal::rng::Rng;
 use esp_println::println;
 use esp_wifi::wifi::WifiDevice;
+use hmac::{Hmac, Mac};
 use log::{log, Level};
+use sha2::Sha256;
 use smoltcp::wire::IpAddress;
 
+use crate::errors::BackendError;
 use crate::utils::now;
 
 pub struct CoapClient<'a, 'b> {
@@ -16,18 +24,249 @@ pub struct CoapClient<'a, 'b> {
     token: u8,
     ip: IpAddress,
     port: u16,
+    rng: Rng,
+    // Answered when the server asks for them, json by path
+    resources: Vec<(&'static str, Vec<u8>)>,
 }
 
 impl<'a, 'b> CoapClient<'a, 'b> {
-    pub fn new(socket: UdpSocket<'a, 'b, WifiDevice<'a>>, ip: IpAddress, port: u16) -> Self {
+    pub fn new(
+        socket: UdpSocket<'a, 'b, WifiDevice<'a>>,
+        ip: IpAddress,
+        port: u16,
+        rng: Rng,
+    ) -> Self {
         Self {
             socket,
             msg_id: 0,
             token: 0,
             ip,
             port,
+            rng,
+            resources: Vec::new(),
         }
     }
+    /// Provisioning can change the server after the client was created
+    pub fn set_server(&mut self, ip: IpAddress, port: u16) {
+        self.ip = ip;
+        self.port = port;
+    }
+
+    /// Makes the device answer GET requests for `uri_path` while observing
+    pub fn serve(&mut self, uri_path: &'static str, payload: Vec<u8>) {
+        self.resources.retain(|(path, _)| *path != uri_path);
+        self.resources.push((uri_path, payload));
+    }
+
+    /// CoAP ping, an empty confirmable message that the server answers with a reset
+    pub fn ping(&mut self) -> Result<(), anyhow::Error> {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.code = MessageClass::Empty;
+        let resp = self.send_and_wait(packet, 5)?;
+        if resp.header.get_type() != MessageType::Reset {
+            return Err(anyhow!("Unexpected response to ping"));
+        }
+        Ok(())
+    }
+
+    /// Checks that the server is up and knows this device before we commit to the configuration.
+    /// The server has to accept a proof made with the device secret, not just the ID
+    pub fn verify_device(
+        &mut self,
+        resource_path: &str,
+        secret: &[u8],
+    ) -> Result<(), BackendError> {
+        if let Err(err) = self.ping() {
+            log!(Level::Debug, "{}", err);
+            return Err(BackendError::Unreachable);
+        }
+        // Proves we hold the secret without sending it, a fresh nonce keeps the proof from being replayed
+        let mut nonce = [0u8; 16];
+        for chunk in nonce.chunks_mut(4) {
+            chunk.copy_from_slice(&self.rng.random().to_le_bytes());
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(&nonce);
+        let proof = mac.finalize().into_bytes();
+        let mut packet = self.create_get_packet(resource_path, true, true, false);
+        packet.add_option(
+            CoapOption::UriQuery,
+            format!("nonce={}", encode_hex(&nonce)).into_bytes(),
+        );
+        packet.add_option(
+            CoapOption::UriQuery,
+            format!("proof={}", encode_hex(&proof)).into_bytes(),
+        );
+        let resp = self
+            .send_and_wait(packet, 5)
+            .map_err(|_| BackendError::Unreachable)?;
+        match resp.header.code {
+            MessageClass::Response(ResponseType::Content) => Ok(()),
+            MessageClass::Response(ResponseType::NotFound) => Err(BackendError::DeviceUnknown),
+            MessageClass::Response(ResponseType::Unauthorized | ResponseType::Forbidden) => {
+                Err(BackendError::NotAuthenticated)
+            }
+            _ => Err(BackendError::Unreachable),
+        }
+    }
+
+    /// GET that waits for its response, unlike the one starting an observe
+    pub fn fetch(&mut self, uri_path: &str) -> Result<Packet, anyhow::Error> {
+        let packet = self.create_get_packet(uri_path, true, true, false);
+        self.send_and_wait(packet, 5)
+    }
+
+    /// One block of a Block2 transfer, returns its payload, whether more blocks follow
+    /// and the block size the server answered with, it may pick a smaller one
+    pub fn fetch_block(
+        &mut self,
+        uri_path: &str,
+        num: u32,
+        szx: u8,
+    ) -> Result<(Vec<u8>, bool, u8), anyhow::Error> {
+        let mut packet = self.create_get_packet(uri_path, true, true, false);
+        packet.add_option(CoapOption::Block2, encode_block(num, false, szx));
+        let resp = self.send_and_wait(packet, 5)?;
+        if !matches!(
+            resp.header.code,
+            MessageClass::Response(ResponseType::Content)
+        ) {
+            return Err(anyhow!(
+                "Unexpected response to block {}: {:?}",
+                num,
+                resp.header.code
+            ));
+        }
+        // Small enough to fit a single response, the server can leave the option out
+        let (resp_num, more, resp_szx) = resp
+            .get_option(CoapOption::Block2)
+            .and_then(|values| values.front())
+            .map(|value| decode_block(value))
+            .unwrap_or((0, false, szx));
+        // A smaller block has another number, it has to start where the requested one does
+        if resp_szx > szx || resp_num * block_size(resp_szx) != num * block_size(szx) {
+            return Err(anyhow!(
+                "Got block {} of size {} instead of {} of size {}",
+                resp_num,
+                block_size(resp_szx),
+                num,
+                block_size(szx)
+            ));
+        }
+        Ok((resp.payload, more, resp_szx))
+    }
+
+    /// Confirmable PUT with a json payload, used to tell the server about local changes
+    pub fn make_put_request(
+        &mut self,
+        uri_path: &str,
+        payload: Vec<u8>,
+    ) -> Result<Packet, anyhow::Error> {
+        self.send_json(RequestType::Put, uri_path, payload)
+    }
+
+    /// Confirmable POST with a json payload, used to publish readings
+    #[cfg_attr(not(feature = "sensor"), allow(dead_code))]
+    pub fn make_post_request(
+        &mut self,
+        uri_path: &str,
+        payload: Vec<u8>,
+    ) -> Result<Packet, anyhow::Error> {
+        self.send_json(RequestType::Post, uri_path, payload)
+    }
+
+    fn send_json(
+        &mut self,
+        method: RequestType,
+        uri_path: &str,
+        payload: Vec<u8>,
+    ) -> Result<Packet, anyhow::Error> {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.code = MessageClass::Request(method);
+        packet.set_token(vec![self.token]);
+        self.token = self.token.wrapping_add(1);
+        uri_path.split('/').for_each(|x| {
+            packet.add_option(CoapOption::UriPath, x.to_string().into_bytes());
+        });
+        packet.set_content_format(ContentFormat::ApplicationJSON);
+        packet.payload = payload;
+        let resp = self.send_and_wait(packet, 5)?;
+        match resp.header.code {
+            MessageClass::Response(ResponseType::Changed)
+            | MessageClass::Response(ResponseType::Created)
+            | MessageClass::Response(ResponseType::Content) => Ok(resp),
+            code => Err(anyhow!("Unexpected response to {:?}: {:?}", method, code)),
+        }
+    }
+
+    /// Sends a confirmable message and waits for the packet answering it.
+    /// Anything else that arrives in the meantime, like an observe notification,
+    /// is dropped and will be resent by the server since we never acknowledged it.
+    fn send_and_wait(&mut self, mut packet: Packet, timeout: u64) -> Result<Packet, anyhow::Error> {
+        packet.header.message_id = self.msg_id;
+        self.msg_id = self.msg_id.wrapping_add(1);
+        let packet_bytes = packet
+            .to_bytes()
+            .map_err(|_| anyhow!("error creating coap packet"))?;
+        self.socket
+            .send(self.ip, self.port, &packet_bytes)
+            .map_err(|_| anyhow!("error sending packet"))?;
+        let wait_end = now() + timeout * 1000;
+        loop {
+            // Receive doesn't block, an error usually just means nothing arrived yet
+            if let Ok(resp) = self.receive(timeout) {
+                if resp.header.message_id == packet.header.message_id {
+                    return Ok(resp);
+                }
+            }
+            Self::check_timeout(wait_end)?;
+        }
+    }
+
+    /// Answers a request from the server, piggybacked on the acknowledgement when it is confirmable
+    fn handle_request(&mut self, request: Packet) {
+        let uri_path = request
+            .get_option(CoapOption::UriPath)
+            .map(|segments| {
+                segments
+                    .iter()
+                    .map(|segment| String::from_utf8_lossy(segment))
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+            .unwrap_or_default();
+        let resource = self
+            .resources
+            .iter()
+            .find(|(path, _)| *path == uri_path)
+            .map(|(_, payload)| payload.clone());
+        let mut packet = Packet::new();
+        if request.header.get_type() == MessageType::Confirmable {
+            packet.header.set_type(MessageType::Acknowledgement);
+            packet.header.message_id = request.header.message_id;
+        } else {
+            packet.header.set_type(MessageType::NonConfirmable);
+            packet.header.message_id = self.msg_id;
+            self.msg_id = self.msg_id.wrapping_add(1);
+        }
+        packet.set_token(request.get_token().to_vec());
+        packet.header.code = match (request.header.code, resource) {
+            (MessageClass::Request(RequestType::Get), Some(payload)) => {
+                packet.set_content_format(ContentFormat::ApplicationJSON);
+                packet.payload = payload;
+                MessageClass::Response(ResponseType::Content)
+            }
+            (_, Some(_)) => MessageClass::Response(ResponseType::MethodNotAllowed),
+            (_, None) => MessageClass::Response(ResponseType::NotFound),
+        };
+        println!("Answering a request for {}", uri_path);
+        if let Ok(packet_bytes) = packet.to_bytes() {
+            let _ = self.socket.send(self.ip, self.port, &packet_bytes);
+        }
+    }
+
     fn handle_acknowledgement(&mut self, resp: Packet) {
         let mut packet = Packet::new();
         packet.header.set_type(MessageType::Acknowledgement);
@@ -133,41 +372,86 @@ impl<'a, 'b> CoapClient<'a, 'b> {
         self.receive(5)
     }
 
-    pub fn make_observe_request<F: FnMut(Vec<u8>) -> Result<(), anyhow::Error>>(
+    /// `idle_callback` runs between received packets, so local events can be
+    /// handled without waiting for the observe to time out
+    pub fn make_observe_request<F, I>(
         &mut self,
         uri_path: &str,
         is_confirmable: bool,
         response_callback: &mut F,
-    ) -> Result<(), anyhow::Error> {
+        idle_callback: &mut I,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(Vec<u8>) -> Result<(), anyhow::Error>,
+        I: FnMut(&mut Self) -> Result<(), anyhow::Error>,
+    {
         let resp = self.make_get_request(uri_path, is_confirmable, true, true);
         if resp.is_err() {
             log!(Level::Debug, "{}", resp.unwrap_err());
             // println!("{:?}", resp.unwrap_err());
         }
-        self.observe(10, response_callback)
+        self.observe(10, response_callback, idle_callback)
     }
 
-    fn observe<F: FnMut(Vec<u8>) -> Result<(), anyhow::Error>>(
+    fn observe<F, I>(
         &mut self,
         timeout: u64,
         mut response_callback: F,
-    ) -> Result<(), anyhow::Error> {
+        mut idle_callback: I,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(Vec<u8>) -> Result<(), anyhow::Error>,
+        I: FnMut(&mut Self) -> Result<(), anyhow::Error>,
+    {
         println!("Observing");
         let mut wait_end = now() + timeout * 1000;
         loop {
             let resp = self.receive(timeout);
             if resp.is_ok() {
                 let resp = resp.unwrap();
-                println!("Handling observe");
-                response_callback(resp.payload.clone())?;
-                self.handle_acknowledgement(resp);
-                self.msg_id += 1;
+                // The server asking for something of ours isn't a notification
+                if let MessageClass::Request(_) = resp.header.code {
+                    self.handle_request(resp);
+                } else {
+                    println!("Handling observe");
+                    response_callback(resp.payload.clone())?;
+                    self.handle_acknowledgement(resp);
+                    self.msg_id += 1;
 
-                wait_end = now() + timeout * 1000;
+                    wait_end = now() + timeout * 1000;
+                }
             } else {
                 log!(Level::Debug, "{}", resp.unwrap_err());
             }
+            idle_callback(self)?;
             Self::check_timeout(wait_end)?;
         }
     }
 }
+
+/// Block option value, `NUM | M | SZX` in as few bytes as possible
+fn encode_block(num: u32, more: bool, szx: u8) -> Vec<u8> {
+    let value = (num << 4) | ((more as u32) << 3) | (szx as u32 & 0x7);
+    let bytes = value.to_be_bytes();
+    let start = bytes
+        .iter()
+        .position(|byte| *byte != 0)
+        .unwrap_or(bytes.len());
+    bytes[start..].to_vec()
+}
+
+fn decode_block(value: &[u8]) -> (u32, bool, u8) {
+    let value = value
+        .iter()
+        .fold(0u32, |value, byte| (value << 8) | *byte as u32);
+    (value >> 4, value & 0x8 != 0, (value & 0x7) as u8)
+}
+
+/// Bytes in a Block2 block of the given size exponent
+pub fn block_size(szx: u8) -> u32 {
+    16 << szx
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
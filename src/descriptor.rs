@@ -0,0 +1,62 @@
+//! What the unit is and what it supports, sent at startup and served on `descriptor`
+use alloc::format;
+use alloc::vec::Vec;
+use esp_println::println;
+use serde::Serialize;
+
+use crate::coap::CoapClient;
+use crate::device::{BrightnessRange, Capabilities, Device};
+use crate::utils::get_hardware_env;
+
+/// Resource the server can GET from the device
+pub const DESCRIPTOR_PATH: &str = "descriptor";
+// CoAP content formats, text/plain and application/json
+const CONTENT_FORMATS: [u16; 2] = [0, 50];
+
+#[derive(Serialize, Debug)]
+pub struct Descriptor {
+    model: &'static str,
+    hardware_revision: &'static str,
+    firmware_version: &'static str,
+    /// Build date and time, tells apart builds of the same version
+    firmware_built: (&'static str, &'static str),
+    capabilities: Capabilities,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    brightness: Option<BrightnessRange>,
+    content_formats: &'static [u16],
+}
+
+impl Descriptor {
+    pub fn new<D: Device>(device: &D) -> Self {
+        let (model, hardware_revision) = get_hardware_env();
+        Self {
+            model,
+            hardware_revision,
+            firmware_version: crate::ESP_APP_DESC.version(),
+            firmware_built: (crate::ESP_APP_DESC.date(), crate::ESP_APP_DESC.time()),
+            capabilities: device.capabilities(),
+            brightness: device.brightness_range(),
+            content_formats: &CONTENT_FORMATS,
+        }
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+/// Tells the server what it is talking to, returns whether it got through
+pub fn register(
+    coap_client: &mut CoapClient,
+    resource_path: &str,
+    descriptor: &Descriptor,
+) -> bool {
+    let uri = format!("{}/{}", resource_path, DESCRIPTOR_PATH);
+    match coap_client.make_put_request(&uri, descriptor.to_json()) {
+        Ok(_) => true,
+        Err(err) => {
+            println!("Could not register the descriptor: {}", err);
+            false
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! What the main loop needs from the thing the firmware drives.
+//! One profile is built in, picked with the `light`, `relay` or `sensor` feature.
+use alloc::string::String;
+use esp_println::println;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::button::ButtonEvent;
+use crate::coap::CoapClient;
+
+#[cfg(any(
+    all(feature = "light", feature = "relay"),
+    all(feature = "light", feature = "sensor"),
+    all(feature = "relay", feature = "sensor"),
+))]
+compile_error!("Only one of the light, relay and sensor features can be enabled");
+
+#[cfg(not(any(feature = "light", feature = "relay", feature = "sensor")))]
+compile_error!("One of the light, relay and sensor features has to be enabled");
+
+/// What the device supports, so the backend knows what to offer
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+pub struct Capabilities {
+    pub on_off: bool,
+    /// Outputs switched separately, e.g. the relays on a board
+    pub channels: u8,
+    pub brightness: bool,
+    pub color: bool,
+    pub effects: bool,
+    pub scenes: bool,
+    pub schedules: bool,
+    /// Only publishes readings, there is nothing to set
+    pub read_only: bool,
+}
+
+/// Brightness values the server can send, the light is off below `min`
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct BrightnessRange {
+    pub min: u8,
+    pub max: u8,
+}
+
+pub trait Device {
+    /// Sent by the server in notifications and reported back when it changes on the device
+    type State: Serialize + DeserializeOwned;
+
+    /// Resource the server publishes the state on, e.g. `lights/{device_id}`
+    fn resource_path(&self, device_id: &str) -> String;
+
+    /// Shown while pairing, with part of the device id added
+    fn name(&self) -> &'static str;
+
+    /// Sent in the BLE advertisement, so the app knows what it found before connecting
+    fn device_type(&self) -> u8;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// `None` when the brightness can't be set
+    fn brightness_range(&self) -> Option<BrightnessRange> {
+        None
+    }
+
+    fn apply(&mut self, state: Self::State);
+
+    fn state(&self) -> Self::State;
+
+    /// Tells the server about a change made on the device
+    fn report(&self, coap_client: &mut CoapClient, device_id: &str) {
+        let payload = serde_json::to_vec(&self.state()).unwrap();
+        if let Err(err) = coap_client.make_put_request(&self.resource_path(device_id), payload) {
+            println!("Could not report the state: {}", err);
+        }
+    }
+
+    /// State in a notification, `None` when there is nothing to apply yet
+    fn decode(&mut self, payload: &[u8]) -> Result<Option<Self::State>, anyhow::Error> {
+        serde_json::from_slice(payload)
+            .map(Some)
+            .map_err(|_| anyhow::anyhow!("Invalid payload (failed conversion from json)"))
+    }
+
+    /// Removed from the owner's account, the next one shouldn't get their state
+    fn is_removed(&self, _state: &Self::State) -> bool {
+        false
+    }
+
+    /// Short and double presses, returns whether the state changed and has to be reported
+    fn on_button(&mut self, _event: ButtonEvent) -> bool {
+        false
+    }
+
+    /// Called from the main loop between notifications, returns whether the state has to be reported
+    fn poll(&mut self, _coap_client: &mut CoapClient, _device_id: &str) -> bool {
+        false
+    }
+}
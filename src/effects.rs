@@ -0,0 +1,156 @@
+//! Light effects, rendered by the light on every ticker interrupt until the next state update
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::light::mix_colors;
+
+// Candle light, used when the effect doesn't come with colours
+const CANDLE_COLOR: i32 = 0xff9329;
+const HUE_STEPS: u32 = 6 * 256;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum EffectKind {
+    Breathe,
+    Strobe,
+    Candle,
+    ColorLoop,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Effect {
+    pub kind: EffectKind,
+    /// 1 is the slowest, 100 the fastest
+    #[serde(default = "default_speed")]
+    pub speed: u8,
+    /// Breathe and strobe go through them one cycle each, colour loop fades between them.
+    /// Without any the light's own colour is used, or the whole colour wheel for colour loop.
+    #[serde(default)]
+    pub colors: Vec<i32>,
+}
+
+fn default_speed() -> u8 {
+    50
+}
+
+impl Effect {
+    /// Length of one cycle for the effect's speed, between `slowest_ms` and `fastest_ms`
+    fn period_ms(&self, slowest_ms: u64, fastest_ms: u64) -> u64 {
+        let speed = self.speed.clamp(1, 100) as u64;
+        slowest_ms - (slowest_ms - fastest_ms) * (speed - 1) / 99
+    }
+
+    fn color_for_cycle(&self, cycle: u64, default: i32) -> i32 {
+        if self.colors.is_empty() {
+            return default;
+        }
+        self.colors[(cycle % self.colors.len() as u64) as usize]
+    }
+}
+
+pub struct RunningEffect {
+    effect: Effect,
+    started_at: u64,
+    random: u32,
+    flicker: u8,
+    flicker_target: u8,
+    next_flicker_at: u64,
+}
+
+impl RunningEffect {
+    pub fn new(effect: Effect, now: u64) -> Self {
+        Self {
+            effect,
+            started_at: now,
+            // Seeded from the clock, flicker only has to look random
+            random: now as u32 | 1,
+            flicker: 255,
+            flicker_target: 255,
+            next_flicker_at: now,
+        }
+    }
+
+    pub fn effect(&self) -> &Effect {
+        &self.effect
+    }
+
+    /// Brightness and colour for this frame, starting from the light's own
+    pub fn frame(&mut self, now: u64, brightness: u8, color: i32) -> (u8, i32) {
+        let elapsed = now.saturating_sub(self.started_at);
+        match self.effect.kind {
+            EffectKind::Breathe => {
+                let period = self.effect.period_ms(8000, 500);
+                let phase = (elapsed % period) as f32 / period as f32;
+                let level = 0.5 - 0.5 * libm::cosf(phase * 2.0 * core::f32::consts::PI);
+                (
+                    (brightness as f32 * level) as u8,
+                    self.effect.color_for_cycle(elapsed / period, color),
+                )
+            }
+            EffectKind::Strobe => {
+                let period = self.effect.period_ms(2000, 60);
+                // Short flash at the start of every cycle
+                let is_lit = elapsed % period < (period / 5).max(20);
+                let color = self.effect.color_for_cycle(elapsed / period, color);
+                (if is_lit { brightness } else { 0 }, color)
+            }
+            EffectKind::Candle => {
+                if now >= self.next_flicker_at {
+                    self.flicker_target = 150 + (self.next_random() % 106) as u8;
+                    self.next_flicker_at = now + self.effect.period_ms(400, 40);
+                }
+                // Ease towards the target so it flickers instead of jumping
+                self.flicker = mix_channel(self.flicker, self.flicker_target, 1, 4);
+                let color = self.effect.color_for_cycle(0, CANDLE_COLOR);
+                ((brightness as u32 * self.flicker as u32 / 255) as u8, color)
+            }
+            EffectKind::ColorLoop => {
+                let period = self.effect.period_ms(60 * 1000, 1000);
+                let position = elapsed % period;
+                let color = if self.effect.colors.is_empty() {
+                    hue_to_color((position * HUE_STEPS as u64 / period) as u32)
+                } else {
+                    // Every colour gets an equal part of the cycle, fading into the next one
+                    let colors = &self.effect.colors;
+                    let part = (period / colors.len() as u64).max(1);
+                    let index = (position / part) as usize % colors.len();
+                    let next = (index + 1) % colors.len();
+                    mix_colors(
+                        colors[index],
+                        colors[next],
+                        (position % part) as i64,
+                        part as i64,
+                    )
+                };
+                (brightness, color)
+            }
+        }
+    }
+
+    fn next_random(&mut self) -> u32 {
+        // xorshift32
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+}
+
+fn mix_channel(from: u8, to: u8, numerator: i32, denominator: i32) -> u8 {
+    (from as i32 + (to as i32 - from as i32) * numerator / denominator) as u8
+}
+
+/// Fully saturated colour for a hue between 0 and `HUE_STEPS`
+fn hue_to_color(hue: u32) -> i32 {
+    let rising = (hue % 256) as i32;
+    let falling = 255 - rising;
+    let (red, green, blue) = match hue / 256 {
+        0 => (255, rising, 0),
+        1 => (falling, 255, 0),
+        2 => (0, 255, rising),
+        3 => (0, falling, 255),
+        4 => (rising, 0, 255),
+        _ => (255, 0, falling),
+    };
+    (red << 16) | (green << 8) | blue
+}
@@ -25,3 +25,63 @@ impl fmt::Debug for PasswordFlashError {
 	}
 }
 impl Error for PasswordFlashError {}
+#[derive(Copy, Clone, PartialEq)]
+pub enum WifiConnectError {
+	InvalidCredentials,
+	NoAccessPoint,
+	WrongPassword,
+	DhcpTimeout,
+}
+impl fmt::Display for WifiConnectError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			WifiConnectError::InvalidCredentials => write!(f, "Could not read Wi-Fi credentials"),
+			WifiConnectError::NoAccessPoint => write!(f, "Network not found"),
+			WifiConnectError::WrongPassword => write!(f, "Could not join the network, check the password"),
+			WifiConnectError::DhcpTimeout => write!(f, "Did not get an IP address"),
+		}
+	}
+}
+impl fmt::Debug for WifiConnectError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self)
+	}
+}
+impl Error for WifiConnectError {}
+#[derive(Copy, Clone, PartialEq)]
+pub enum BackendError {
+	Unreachable,
+	DeviceUnknown,
+	NotAuthenticated,
+}
+impl fmt::Display for BackendError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			BackendError::Unreachable => write!(f, "Server is not reachable"),
+			BackendError::DeviceUnknown => write!(f, "Server does not know this device"),
+			BackendError::NotAuthenticated => write!(f, "Server did not accept the device secret"),
+		}
+	}
+}
+impl fmt::Debug for BackendError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self)
+	}
+}
+impl Error for BackendError {}
+/// Flash still held data after a reset tried to erase it
+#[derive(Copy, Clone, PartialEq)]
+pub struct ResetVerificationError {
+	pub address: u32,
+}
+impl fmt::Display for ResetVerificationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Flash at {:#x} was not erased", self.address)
+	}
+}
+impl fmt::Debug for ResetVerificationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self)
+	}
+}
+impl Error for ResetVerificationError {}
@@ -0,0 +1,208 @@
+//! Improv Wi-Fi over BLE, see https://www.improv-wifi.com/ble/
+//! Lets any Improv-capable client provision the light next to our own app.
+use alloc::vec;
+use alloc::vec::Vec;
+
+// 00467768-6228-2272-4663-277478268000 in little endian, the way it is sent in advertising data
+pub const IMPROV_SERVICE_UUID_BYTES: [u8; 16] = [
+    0x00, 0x80, 0x26, 0x78, 0x74, 0x27, 0x63, 0x46, 0x72, 0x22, 0x28, 0x62, 0x68, 0x77, 0x46, 0x00,
+];
+pub const IMPROV_SERVICE_DATA_UUID: u16 = 0x4677;
+// The identify command isn't implemented, so no capability bits are set
+pub const IMPROV_CAPABILITIES: u8 = 0x00;
+// How long a button press keeps the device authorized
+const AUTHORIZATION_TIMEOUT_MS: u64 = 60 * 1000;
+
+const RPC_WIFI_SETTINGS: u8 = 0x01;
+const RPC_DEVICE_INFO: u8 = 0x03;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ImprovState {
+    AuthorizationRequired = 0x02,
+    Authorized = 0x03,
+    Provisioning = 0x04,
+    Provisioned = 0x05,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ImprovError {
+    None = 0x00,
+    InvalidRpc = 0x01,
+    UnknownRpc = 0x02,
+    UnableToConnect = 0x03,
+    NotAuthorized = 0x04,
+    Unknown = 0xff,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ImprovCharacteristic {
+    State,
+    Error,
+    RpcResult,
+}
+
+enum ImprovCommand {
+    WifiSettings { ssid: Vec<u8>, password: Vec<u8> },
+    DeviceInfo,
+}
+
+pub struct ImprovService {
+    pub state: ImprovState,
+    pub error: ImprovError,
+    pub rpc_result: Vec<u8>,
+    authorized_until: u64,
+    rpc_buf: Vec<u8>,
+    wifi_settings: Option<(Vec<u8>, Vec<u8>)>,
+    notifications: Vec<(ImprovCharacteristic, Vec<u8>)>,
+}
+
+impl ImprovService {
+    pub fn new() -> Self {
+        Self {
+            state: ImprovState::AuthorizationRequired,
+            error: ImprovError::None,
+            rpc_result: vec![],
+            authorized_until: 0,
+            rpc_buf: vec![],
+            wifi_settings: None,
+            notifications: vec![],
+        }
+    }
+
+    /// Called while the button is pressed, Improv wants physical presence before provisioning
+    pub fn authorize(&mut self, now: u64) {
+        self.authorized_until = now + AUTHORIZATION_TIMEOUT_MS;
+        if self.state == ImprovState::AuthorizationRequired {
+            self.set_state(ImprovState::Authorized);
+        }
+    }
+
+    pub fn check_authorization_timeout(&mut self, now: u64) {
+        if self.state == ImprovState::Authorized && now > self.authorized_until {
+            self.set_state(ImprovState::AuthorizationRequired);
+        }
+    }
+
+    pub fn set_state(&mut self, state: ImprovState) {
+        self.state = state;
+        self.notifications
+            .push((ImprovCharacteristic::State, vec![state as u8]));
+    }
+
+    pub fn set_error(&mut self, error: ImprovError) {
+        self.error = error;
+        self.notifications
+            .push((ImprovCharacteristic::Error, vec![error as u8]));
+    }
+
+    pub fn take_notification(&mut self) -> Option<(ImprovCharacteristic, Vec<u8>)> {
+        if self.notifications.is_empty() {
+            return None;
+        }
+        Some(self.notifications.remove(0))
+    }
+
+    pub fn take_wifi_settings(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.wifi_settings.take()
+    }
+
+    /// Called once the credentials were written to flash and the connection is up
+    pub fn provisioned(&mut self) {
+        self.set_state(ImprovState::Provisioned);
+        // We don't have a web interface to redirect to, so no urls
+        self.set_rpc_result(RPC_WIFI_SETTINGS, &[]);
+    }
+
+    pub fn provisioning_failed(&mut self, error: ImprovError) {
+        self.set_error(error);
+        self.set_state(ImprovState::Authorized);
+    }
+
+    /// RPC packets can be split between multiple writes, so they are buffered
+    /// until the length from the header has been received
+    pub fn handle_rpc_write(&mut self, data: &[u8], device_name: &[u8]) {
+        self.rpc_buf.extend_from_slice(data);
+        if self.rpc_buf.len() < 2 || self.rpc_buf.len() < self.rpc_buf[1] as usize + 3 {
+            return;
+        }
+        let packet: Vec<u8> = self.rpc_buf.drain(..).collect();
+        if self.error != ImprovError::None {
+            self.set_error(ImprovError::None);
+        }
+        match parse_rpc(&packet) {
+            Ok(ImprovCommand::WifiSettings { ssid, password }) => {
+                if self.state != ImprovState::Authorized {
+                    self.set_error(ImprovError::NotAuthorized);
+                    return;
+                }
+                self.set_state(ImprovState::Provisioning);
+                self.wifi_settings = Some((ssid, password));
+            }
+            Ok(ImprovCommand::DeviceInfo) => {
+                self.set_rpc_result(
+                    RPC_DEVICE_INFO,
+                    &[
+                        b"diy-iot-esp-firmware",
+                        env!("CARGO_PKG_VERSION").as_bytes(),
+                        b"ESP32",
+                        device_name,
+                    ],
+                );
+            }
+            Err(error) => self.set_error(error),
+        }
+    }
+
+    fn set_rpc_result(&mut self, command: u8, strings: &[&[u8]]) {
+        self.rpc_result = build_rpc_result(command, strings);
+        self.notifications
+            .push((ImprovCharacteristic::RpcResult, self.rpc_result.clone()));
+    }
+}
+
+impl Default for ImprovService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Improv checksum is the sum of all previous bytes, truncated to one byte
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn parse_rpc(packet: &[u8]) -> Result<ImprovCommand, ImprovError> {
+    let data_len = packet[1] as usize;
+    let (body, received_checksum) = packet.split_at(data_len + 2);
+    if checksum(body) != received_checksum[0] {
+        return Err(ImprovError::InvalidRpc);
+    }
+    let data = &body[2..];
+    match packet[0] {
+        RPC_WIFI_SETTINGS => {
+            let ssid_len = *data.first().ok_or(ImprovError::InvalidRpc)? as usize;
+            let ssid = data.get(1..1 + ssid_len).ok_or(ImprovError::InvalidRpc)?;
+            let password_len = *data.get(1 + ssid_len).ok_or(ImprovError::InvalidRpc)? as usize;
+            let password = data
+                .get(2 + ssid_len..2 + ssid_len + password_len)
+                .ok_or(ImprovError::InvalidRpc)?;
+            Ok(ImprovCommand::WifiSettings {
+                ssid: ssid.to_vec(),
+                password: password.to_vec(),
+            })
+        }
+        RPC_DEVICE_INFO => Ok(ImprovCommand::DeviceInfo),
+        _ => Err(ImprovError::UnknownRpc),
+    }
+}
+
+fn build_rpc_result(command: u8, strings: &[&[u8]]) -> Vec<u8> {
+    let mut result = vec![command, 0];
+    for string in strings {
+        result.push(string.len() as u8);
+        result.extend_from_slice(string);
+    }
+    result[1] = (result.len() - 2) as u8;
+    result.push(checksum(&result));
+    result
+}
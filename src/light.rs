@@ -0,0 +1,260 @@
+//! Light output, driven both by the server and by the button.
+//! Lives in a global so the ticker can keep fading while the main loop waits on the network.
+use core::cell::RefCell;
+
+use critical_section::{CriticalSection, Mutex};
+use esp_hal::analog::dac::Dac;
+use esp_hal::peripherals::DAC2;
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize};
+
+use crate::brightness::{load_calibration, BrightnessMap, Curve};
+use crate::effects::{Effect, RunningEffect};
+use crate::power_on::PowerOn;
+use crate::rgb::{self, RgbOutput};
+use crate::status_led;
+use crate::utils::now;
+
+// Lights that never had a colour picked come with 0, which would be dark
+const WHITE: i32 = 0xffffff;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct LightState {
+    pub is_on: bool,
+    pub brightness: u8,
+    pub color: i32,
+    pub removed: bool,
+    // How long to fade into this state, the server doesn't keep it
+    #[serde(default, skip_serializing)]
+    pub transition_ms: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<Effect>,
+    // Scene the state was recalled from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scene: Option<u8>,
+    #[serde(default, skip_serializing)]
+    pub scenes_version: Option<u32>,
+    #[serde(default, skip_serializing)]
+    pub schedules_version: Option<u32>,
+    // Only sent when the owner changes it
+    #[serde(default, skip_serializing)]
+    pub power_on: Option<PowerOn>,
+}
+
+pub enum LightOutput<'a> {
+    /// Single channel dimmer on GPIO26
+    Dac(Dac<'a, DAC2<'a>>),
+    Rgb(RgbOutput),
+}
+
+impl LightOutput<'_> {
+    /// Used until the board gets calibrated
+    fn default_brightness_map(&self) -> BrightnessMap {
+        match self {
+            // What the driver boards we started with respond to
+            LightOutput::Dac(_) => BrightnessMap {
+                curve: Curve::Cie1931,
+                gamma: 1.0,
+                min_output: 200,
+                max_output: 251,
+            },
+            LightOutput::Rgb(_) => BrightnessMap {
+                curve: Curve::Cie1931,
+                gamma: 1.0,
+                min_output: 1,
+                max_output: rgb::MAX_DUTY,
+            },
+        }
+    }
+}
+
+/// What is actually on the output, brightness 0 when the light is off
+#[derive(Copy, Clone, PartialEq)]
+struct Rendered {
+    brightness: u8,
+    color: i32,
+}
+
+struct Transition {
+    from: Rendered,
+    to: Rendered,
+    started_at: u64,
+    duration_ms: u64,
+}
+
+impl Transition {
+    fn at(&self, now: u64) -> Rendered {
+        let elapsed = now.saturating_sub(self.started_at).min(self.duration_ms) as i64;
+        let duration = self.duration_ms as i64;
+        let brightness = self.from.brightness as i64
+            + (self.to.brightness as i64 - self.from.brightness as i64) * elapsed / duration;
+        Rendered {
+            brightness: brightness as u8,
+            color: mix_colors(self.from.color, self.to.color, elapsed, duration),
+        }
+    }
+
+    fn is_done(&self, now: u64) -> bool {
+        now >= self.started_at + self.duration_ms
+    }
+}
+
+/// Each of the red, green and blue parts moved `numerator / denominator` of the way from `from` to `to`
+pub(crate) fn mix_colors(from: i32, to: i32, numerator: i64, denominator: i64) -> i32 {
+    [16, 8, 0].iter().fold(0, |color, shift| {
+        let from = ((from >> shift) & 0xff) as i64;
+        let to = ((to >> shift) & 0xff) as i64;
+        color | ((from + (to - from) * numerator / denominator) as i32) << shift
+    })
+}
+
+fn target_color(state: &LightState) -> i32 {
+    if state.color == 0 {
+        WHITE
+    } else {
+        state.color
+    }
+}
+
+struct Light {
+    output: LightOutput<'static>,
+    brightness_map: BrightnessMap,
+    state: LightState,
+    rendered: Rendered,
+    transition: Option<Transition>,
+    effect: Option<RunningEffect>,
+}
+
+static LIGHT: Mutex<RefCell<Option<Light>>> = Mutex::new(RefCell::new(None));
+
+pub fn init(output: LightOutput<'static>) {
+    let brightness_map =
+        load_calibration(&mut FlashStorage::new(), output.default_brightness_map());
+    let mut light = Light {
+        output,
+        brightness_map,
+        // Off until the server tells us otherwise
+        state: LightState {
+            is_on: false,
+            brightness: 255,
+            color: 0,
+            removed: false,
+            transition_ms: 0,
+            effect: None,
+            scene: None,
+            scenes_version: None,
+            schedules_version: None,
+            power_on: None,
+        },
+        rendered: Rendered {
+            brightness: 0,
+            color: WHITE,
+        },
+        transition: None,
+        effect: None,
+    };
+    light.write(light.rendered);
+    critical_section::with(|cs| LIGHT.borrow_ref_mut(cs).replace(light));
+}
+
+/// Fades to `state` over its `transition_ms`, or switches right away without one
+pub fn apply(state: LightState) {
+    critical_section::with(|cs| {
+        if let Some(light) = LIGHT.borrow_ref_mut(cs).as_mut() {
+            light.apply(state, now());
+        }
+    });
+}
+
+pub fn state() -> LightState {
+    critical_section::with(|cs| {
+        let light = LIGHT.borrow_ref(cs);
+        light
+            .as_ref()
+            .expect("Light is not initialized")
+            .state
+            .clone()
+    })
+}
+
+/// Returns the state the light is going to, to be reported to the server
+pub fn toggle(transition_ms: u32) -> LightState {
+    critical_section::with(|cs| {
+        let mut light = LIGHT.borrow_ref_mut(cs);
+        let light = light.as_mut().expect("Light is not initialized");
+        let mut state = light.state.clone();
+        state.is_on = !state.is_on;
+        state.transition_ms = transition_ms;
+        light.apply(state.clone(), now());
+        state
+    })
+}
+
+pub(crate) fn tick(cs: CriticalSection, now: u64) {
+    let mut light = LIGHT.borrow_ref_mut(cs);
+    let Some(light) = light.as_mut() else {
+        return;
+    };
+    let color = target_color(&light.state);
+    let brightness = light.state.brightness;
+    let rendered = if let Some(effect) = light.effect.as_mut() {
+        let (brightness, color) = effect.frame(now, brightness, color);
+        Rendered { brightness, color }
+    } else if let Some(transition) = light.transition.as_ref() {
+        let rendered = transition.at(now);
+        if transition.is_done(now) {
+            light.transition = None;
+        }
+        rendered
+    } else {
+        return;
+    };
+    if rendered != light.rendered {
+        light.write(rendered);
+    }
+}
+
+impl Light {
+    fn apply(&mut self, state: LightState, now: u64) {
+        // Any other update stops the running effect, fading from wherever it was
+        self.effect = match (&state.effect, state.is_on, self.effect.take()) {
+            (Some(effect), true, Some(running)) if running.effect() == effect => Some(running),
+            (Some(effect), true, _) => Some(RunningEffect::new(effect.clone(), now)),
+            _ => None,
+        };
+        let color = target_color(&state);
+        let target = Rendered {
+            brightness: if state.is_on { state.brightness } else { 0 },
+            color,
+        };
+        let mut from = self.rendered;
+        // Coming from off, fade in with the new colour instead of through the old one
+        if from.brightness == 0 {
+            from.color = color;
+        }
+        if self.effect.is_some() {
+            self.transition = None;
+        } else if state.transition_ms == 0 {
+            self.transition = None;
+            self.write(target);
+        } else {
+            self.transition = Some(Transition {
+                from,
+                to: target,
+                started_at: now,
+                duration_ms: state.transition_ms as u64,
+            });
+        }
+        status_led::set_light_on(state.is_on);
+        self.state = state;
+    }
+
+    fn write(&mut self, rendered: Rendered) {
+        let output = self.brightness_map.output(rendered.brightness);
+        match &mut self.output {
+            LightOutput::Dac(dac) => dac.write(output.min(u8::MAX as u16) as u8),
+            LightOutput::Rgb(rgb) => rgb.write(rendered.color, output),
+        }
+        self.rendered = rendered;
+    }
+}
@@ -0,0 +1,173 @@
+//! Dimmable and RGB lights as a `Device`, with their scenes, schedules and power-on state
+use alloc::format;
+use alloc::string::String;
+use anyhow::anyhow;
+use esp_println::println;
+use esp_storage::FlashStorage;
+
+use crate::button::ButtonEvent;
+use crate::coap::CoapClient;
+use crate::device::{BrightnessRange, Capabilities, Device};
+use crate::light::{self, LightOutput, LightState};
+use crate::power_on::{PowerOn, StateKeeper};
+use crate::scenes::{load_scenes, sync_scenes, SceneRecall, SceneStore};
+use crate::schedules::{load_schedules, Scheduler};
+
+const BUTTON_TRANSITION_MS: u32 = 300;
+
+pub struct LightDevice {
+    has_color: bool,
+    scenes: SceneStore,
+    // Fetching needs the client, so notifications only flag it
+    scenes_outdated: bool,
+    // Recalled while the scenes were outdated
+    pending_scene: Option<u8>,
+    scheduler: Scheduler,
+    schedules_outdated: bool,
+    state_keeper: StateKeeper,
+    // The power-on policy picked a state the server doesn't know about
+    needs_report: bool,
+}
+
+impl LightDevice {
+    /// Applies the power-on state right away, joining the network can take a while
+    pub fn new(output: LightOutput<'static>, fs: &mut FlashStorage) -> Self {
+        let has_color = matches!(output, LightOutput::Rgb(_));
+        light::init(output);
+        let scenes = load_scenes(fs);
+        let state_keeper = StateKeeper::load(fs);
+        if let Some(state) = state_keeper.power_on_state(&scenes) {
+            light::apply(state);
+        }
+        Self {
+            has_color,
+            scenes,
+            // Both are checked once at boot as well, the schedules reply also sets the clock
+            scenes_outdated: true,
+            pending_scene: None,
+            scheduler: Scheduler::new(load_schedules(fs)),
+            schedules_outdated: true,
+            needs_report: state_keeper.power_on() != PowerOn::LastState,
+            state_keeper,
+        }
+    }
+
+    fn is_outdated(&self, scenes_version: Option<u32>) -> bool {
+        scenes_version.is_some_and(|version| version != self.scenes.version)
+    }
+}
+
+impl Device for LightDevice {
+    type State = LightState;
+
+    fn resource_path(&self, device_id: &str) -> String {
+        format!("lights/{}", device_id)
+    }
+
+    fn name(&self) -> &'static str {
+        "Fancy lights"
+    }
+
+    fn device_type(&self) -> u8 {
+        0x01
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            on_off: true,
+            channels: 1,
+            brightness: true,
+            color: self.has_color,
+            effects: true,
+            scenes: true,
+            schedules: true,
+            read_only: false,
+        }
+    }
+
+    fn brightness_range(&self) -> Option<BrightnessRange> {
+        Some(BrightnessRange { min: 1, max: 255 })
+    }
+
+    fn apply(&mut self, state: LightState) {
+        if self.is_outdated(state.scenes_version) {
+            self.scenes_outdated = true;
+        }
+        if state
+            .schedules_version
+            .is_some_and(|version| version != self.scheduler.version())
+        {
+            self.schedules_outdated = true;
+        }
+        if let Some(power_on) = state.power_on {
+            self.state_keeper.set_power_on(power_on);
+        }
+        light::apply(state);
+    }
+
+    fn state(&self) -> LightState {
+        light::state()
+    }
+
+    fn decode(&mut self, payload: &[u8]) -> Result<Option<LightState>, anyhow::Error> {
+        if let Ok(state) = serde_json::from_slice(payload) {
+            return Ok(Some(state));
+        }
+        // Group scene changes only send the scene
+        let recall: SceneRecall = serde_json::from_slice(payload)
+            .map_err(|_| anyhow!("Invalid payload (failed conversion from json)"))?;
+        match self.scenes.find(recall.scene) {
+            Some(scene) if !self.is_outdated(recall.scenes_version) => Ok(Some(scene.to_state())),
+            _ => {
+                self.pending_scene = Some(recall.scene);
+                self.scenes_outdated = true;
+                Ok(None)
+            }
+        }
+    }
+
+    fn is_removed(&self, state: &LightState) -> bool {
+        state.removed
+    }
+
+    fn on_button(&mut self, event: ButtonEvent) -> bool {
+        match event {
+            ButtonEvent::ShortPress => {
+                light::toggle(BUTTON_TRANSITION_MS);
+                println!("Light toggled with the button");
+                true
+            }
+            ButtonEvent::DoublePress => {
+                let Some(scene) = self.scenes.next_after(light::state().scene) else {
+                    println!("No scenes to cycle through");
+                    return false;
+                };
+                println!("Recalling scene {}", scene.name);
+                light::apply(scene.to_state());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn poll(&mut self, coap_client: &mut CoapClient, device_id: &str) -> bool {
+        let mut changed = core::mem::take(&mut self.needs_report);
+        if core::mem::take(&mut self.scenes_outdated) {
+            changed |= sync_scenes(
+                coap_client,
+                device_id,
+                &mut self.scenes,
+                self.pending_scene.take(),
+            );
+        }
+        if core::mem::take(&mut self.schedules_outdated) {
+            self.scheduler.sync(coap_client, device_id);
+        }
+        if let Some(state) = self.scheduler.poll(&self.scenes) {
+            light::apply(state);
+            changed = true;
+        }
+        self.state_keeper.poll();
+        changed
+    }
+}
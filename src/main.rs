@@ -4,17 +4,46 @@
 #![no_main]
 extern crate alloc;
 
-use crate::utils::{get_device_data, get_env, handle_device_reset, init_hardware};
-use alloc::format;
+use crate::button::ButtonEvent;
+use crate::coap::CoapClient;
+use crate::descriptor::{Descriptor, DESCRIPTOR_PATH};
+use crate::device::Device;
+#[cfg(feature = "light")]
+use crate::light::LightOutput;
+#[cfg(feature = "light")]
+use crate::light_device::LightDevice;
+use crate::ota::Updater;
+use crate::pairing::PairingIdentity;
+#[cfg(feature = "relay")]
+use crate::relay::Relay;
+use crate::reset::{handle_device_reset, ResetLevel};
+#[cfg(feature = "light")]
+use crate::rgb::RgbOutput;
+#[cfg(feature = "sensor")]
+use crate::sensor::Sensor;
+use crate::sntp::SntpClient;
+use crate::status_led::Status;
+#[cfg(feature = "relay")]
+use crate::utils::get_relay_env;
+#[cfg(feature = "light")]
+use crate::utils::get_rgb_env;
+#[cfg(feature = "sensor")]
+use crate::utils::get_sensor_env;
+use crate::utils::{
+    get_device_data, get_env, get_server_config, get_time_env, init_hardware, request_maintenance,
+    DevicePeripherals, Hardware,
+};
 use alloc::string::String;
 use anyhow::anyhow;
 use blocking_network_stack::Stack;
+use core::cell::RefCell;
 use esp_alloc as _;
 use esp_backtrace as _;
+#[cfg(feature = "light")]
 use esp_hal::analog::dac::Dac;
 use esp_hal::gpio::{Input, Level, Output, OutputConfig, Pull};
 use esp_hal::main;
-use esp_hal::peripherals::{DAC2, GPIO2, GPIO26, GPIO4};
+use esp_hal::peripherals::{GPIO2, GPIO4};
 
 use crate::wifi_utils::{
     init_stack_sockets, initialize_network_or_pair, setup_udp_socket, setup_udp_socket_params,
@@ -22,51 +51,106 @@ use crate::wifi_utils::{
 use esp_println::println;
 use esp_storage::FlashStorage;
 use esp_wifi::wifi::WifiController;
-use serde::{Deserialize, Serialize};
+use smoltcp::wire::IpAddress;
 use utils::now;
 
 esp_bootloader_esp_idf::esp_app_desc!();
 
+mod ble_security;
+#[cfg(feature = "light")]
+mod brightness;
+mod button;
+mod captive_portal;
+mod clock;
 mod coap;
+mod descriptor;
+mod device;
+#[cfg(feature = "light")]
+mod effects;
 mod errors;
+#[cfg(feature = "improv")]
+mod improv;
+#[cfg(feature = "light")]
+mod light;
+#[cfg(feature = "light")]
+mod light_device;
+mod ota;
 mod pairing;
+mod pairing_status;
+#[cfg(feature = "light")]
+mod power_on;
+#[cfg(feature = "relay")]
+mod relay;
+mod reset;
+#[cfg(feature = "light")]
+mod rgb;
+#[cfg(feature = "light")]
+mod scenes;
+#[cfg(feature = "light")]
+mod schedules;
+#[cfg(feature = "sensor")]
+mod sensor;
+#[cfg(feature = "sensor")]
+mod sht3x;
+mod sntp;
+mod status_led;
+mod ticker;
 mod utils;
 mod wifi_utils;
 
-const CONFIG_ADDR: u32 = 0x9000;
-const SSID_ADDR: u32 = 0x9080;
-const PASS_ADDR: u32 = 0x9080 + 128;
-const ID_ADDR: u32 = 0x9080 + 256;
+// Device id and secret from the factory, nothing else is written to their sector
+const ID_ADDR: u32 = 0x9180;
+// Device secret is 344 bytes long
 const SECRET_ADDR: u32 = ID_ADDR + 36;
-
-#[derive(Serialize, Deserialize, Clone)]
-pub struct LightState {
-    pub is_on: bool,
-    pub brightness: u8,
-    pub color: i32,
-    pub removed: bool,
-}
+// Writing anything erases the whole sector it is in
+const FLASH_SECTOR_SIZE: u32 = 0x1000;
+// Network settings, in a sector of their own so rewriting them can't take the identity along
+const CONFIG_ADDR: u32 = 0xA000;
+const SSID_ADDR: u32 = CONFIG_ADDR + 0x80;
+const PASS_ADDR: u32 = SSID_ADDR + 128;
+const SERVER_ADDR: u32 = PASS_ADDR + 128;
+// 6 byte server address, rounded up to keep the flag word aligned
+const MAINTENANCE_ADDR: u32 = SERVER_ADDR + 8;
+// Brightness calibration of the board, kept through a factory reset
+const CALIBRATION_ADDR: u32 = 0xB000;
+// 12 byte header and 17 table points, rounded up to whole words
+#[cfg_attr(not(feature = "light"), allow(dead_code))]
+const CALIBRATION_SIZE: u32 = 48;
+// Lowest firmware security version that may be installed, kept through a factory reset.
+// In a sector of its own, writing the calibration with esptool erases the whole calibration sector
+const SECURITY_VERSION_ADDR: u32 = 0xC000;
+const SECURITY_VERSION_SIZE: u32 = 4;
+// Light state, written every few minutes, so it gets two sectors of its own and a power cut
+// can't take scenes and schedules with it. Also erased by a user reset
+const LIGHT_STATE_ADDR: u32 = 0xD000;
+const LIGHT_STATE_SIZE: u32 = 2 * FLASH_SECTOR_SIZE;
+// Only one device profile is built in, so relays reuse the light state's sectors
+#[cfg(feature = "relay")]
+const RELAY_STATE_ADDR: u32 = LIGHT_STATE_ADDR;
+// End of the nvs partition, see partitions.csv
+const NVS_END_ADDR: u32 = 0xF000;
+// Scenes and schedules, erased by a user reset. The nvs partition is full,
+// they are in the user partition after phy_init, see partitions.csv
+const USER_STATE_ADDR: u32 = 0x12000;
+const USER_STATE_SIZE: u32 = 0x4000;
+// Both get two alternating sectors, so a power cut while storing keeps the old ones
+#[cfg_attr(not(feature = "light"), allow(dead_code))]
+const SCENES_ADDR: u32 = USER_STATE_ADDR;
+#[cfg_attr(not(feature = "light"), allow(dead_code))]
+const SCHEDULES_ADDR: u32 = SCENES_ADDR + 2 * FLASH_SECTOR_SIZE;
 
 pub struct ESPGpio<'a> {
-    pub gpio26_dac: Dac<'a, DAC2<'a>>,
     pub gpio2: Output<'a>,
     pub gpio4: Input<'a>,
 }
 
-fn init_gpio<'a>(
-    gpio2: GPIO2<'a>,
-    dac2: DAC2<'a>,
-    gpio26: GPIO26<'a>,
-    gpio4: GPIO4<'a>,
-) -> ESPGpio<'a> {
+fn init_gpio<'a>(gpio2: GPIO2<'a>, gpio4: GPIO4<'a>) -> ESPGpio<'a> {
     let digital_pin = Output::new(gpio2, Level::Low, OutputConfig::default());
-    let dac1 = esp_hal::analog::dac::Dac::new(dac2, gpio26);
     let reset_pin = Input::new(
         gpio4,
         esp_hal::gpio::InputConfig::default().with_pull(Pull::Down),
     );
     ESPGpio {
-        gpio26_dac: dac1,
         gpio2: digital_pin,
         gpio4: reset_pin,
     }
@@ -79,7 +163,18 @@ fn main() -> ! {
     esp_alloc::heap_allocator!(#[unsafe(link_section = ".dram2_uninit")] size: 96 * 1024);
     esp_alloc::heap_allocator!(size: 24 * 1024);
 
-    let (mut rng, hci, mut controller, iface, device, gpio26, gpio2, gpio4, dac2) = init_hardware();
+    let Hardware {
+        mut rng,
+        hci,
+        mut controller,
+        iface,
+        device: wifi_device,
+        ap_device,
+        gpio2,
+        gpio4,
+        ticker_timer,
+        device_peripherals,
+    } = init_hardware();
     let mut fs = FlashStorage::new();
     let (port_env, ip_address, debug_env) = get_env();
 
@@ -88,16 +183,24 @@ fn main() -> ! {
     let mut socket_set_storage = Default::default();
     let socket_set = init_stack_sockets(&mut socket_set_storage);
 
-    let stack = Stack::new(iface, device, socket_set, now, rng.random());
+    let stack = Stack::new(iface, wifi_device, socket_set, now, rng.random());
 
-    let mut gpio_pins = init_gpio(gpio2, dac2, gpio26, gpio4);
+    let ESPGpio {
+        gpio2: debug_led,
+        gpio4: reset_pin,
+    } = init_gpio(gpio2, gpio4);
 
-    if gpio_pins.gpio4.is_high() {
-        handle_device_reset(&mut fs);
+    // Holding the button at boot only forgets the network, like it always did
+    if reset_pin.is_high() {
+        handle_device_reset(&mut fs, ResetLevel::Network);
     }
+    // From here on the button and the status LED are handled by the ticker
+    button::init(reset_pin);
+    status_led::init(debug_led, debug_env);
+    ticker::start(ticker_timer);
 
-    initialize_network_or_pair(&hci, &mut controller, &mut fs, &stack);
-    println!("Start busy loop on main");
+    // Up before the network, which can take a while to join
+    let device = init_device(device_peripherals, &mut fs);
 
     let mut wrapper = setup_udp_socket_params();
     let mut udp_socket = setup_udp_socket(&stack, &mut wrapper);
@@ -109,7 +212,60 @@ fn main() -> ! {
     if let Err(_err) = udp_socket.bind(socket_port) {
         println!("IoError ");
     }
-    let mut coap_client = coap::CoapClient::new(udp_socket, ip_address, port_env);
+    let (ip_address, port_env) = get_server_config(&mut fs).unwrap_or((ip_address, port_env));
+    // Created before pairing, which uses it to check that the server knows us
+    let mut coap_client = coap::CoapClient::new(udp_socket, ip_address, port_env, rng);
+
+    initialize_network_or_pair(
+        &hci,
+        &mut controller,
+        &mut fs,
+        &stack,
+        ap_device,
+        &mut coap_client,
+        &PairingIdentity::new(&device, &device_id),
+        rng,
+    );
+    // BLE is only used for pairing, dropping the connector frees its buffers
+    drop(hci);
+    // Presses made during pairing were meant for it
+    button::clear_events();
+
+    let (ntp_server, time_zone) = get_time_env();
+    if let Some(time_zone) = time_zone {
+        clock::set_time_zone(time_zone);
+    }
+    // DHCP option 42 isn't used: smoltcp's DHCP socket takes the server's replies before
+    // any other socket sees them and the network stack only passes on address, gateway and DNS.
+    // Without a configured server the gateway is asked instead, most home routers answer
+    let ntp_server = ntp_server.or_else(|| {
+        let gateway = stack
+            .get_ip_info()
+            .ok()
+            .map(|ip_info| IpAddress::Ipv4(ip_info.subnet.gateway));
+        if let Some(gateway) = gateway {
+            println!("No NTP_SERVER set, trying the gateway {}", gateway);
+        }
+        gateway
+    });
+    let mut sntp_wrapper = setup_udp_socket_params();
+    let mut sntp_client = ntp_server.map(|ntp_server| {
+        let mut sntp_socket = setup_udp_socket(&stack, &mut sntp_wrapper);
+        if let Err(_err) = sntp_socket.bind(socket_port + 1) {
+            println!("IoError ");
+        }
+        SntpClient::new(sntp_socket, ntp_server)
+    });
+    println!("Start busy loop on main");
+
+    let device = RefCell::new(device);
+    let uri = device.borrow().resource_path(&device_id);
+    let descriptor = Descriptor::new(&*device.borrow());
+    println!("{:?}", descriptor);
+    coap_client.serve(DESCRIPTOR_PATH, descriptor.to_json());
+    // Retried every time around the loop until the server took it
+    let mut is_registered = false;
+    let mut updater = Updater::default();
 
     let observe_callback = &mut |payload| {
         let payload = String::from_utf8(payload);
@@ -118,41 +274,101 @@ fn main() -> ! {
             return Err(anyhow!("Invalid payload ( failed to convert from utf8 )"));
         }
         let payload = payload.unwrap();
-        let device_state: Result<LightState, serde_json::Error> = serde_json::from_str(&payload);
-        if device_state.is_err() {
-            return Err(anyhow!("Invalid payload (failed conversion from json)"));
-        }
-        let device_state = device_state.unwrap();
-        if device_state.removed {
-            handle_device_reset(&mut fs);
-        }
-        if device_state.is_on {
-            let mut actual_brightness = device_state.brightness;
-            actual_brightness /= 5;
-            gpio_pins.gpio26_dac.write(200 + actual_brightness);
-            if debug_env {
-                gpio_pins.gpio2.set_high();
-            }
-        } else {
-            gpio_pins.gpio26_dac.write(0);
-            gpio_pins.gpio2.set_low();
+        let Some(state) = device.borrow_mut().decode(payload.as_bytes())? else {
+            return Ok(());
+        };
+        // Removed from the owner's account, the next one shouldn't get their state
+        if device.borrow().is_removed(&state) {
+            handle_device_reset(&mut fs, ResetLevel::User);
         }
+        device.borrow_mut().apply(state);
         println!("{}", payload);
         Ok(())
     };
 
     loop {
         println!("{}", controller.is_connected().unwrap());
+        // Observing doesn't notice a dead server, it just stays quiet
+        match coap_client.ping() {
+            Ok(()) => status_led::set(Status::Idle),
+            Err(_) => status_led::set(Status::ServerUnreachable),
+        }
+        if !is_registered {
+            is_registered = descriptor::register(&mut coap_client, &uri, &descriptor);
+            // Reaching the server is as far as an update has to get to be kept
+            if is_registered {
+                ota::mark_valid();
+            }
+        }
         println!("Making Coap request");
-        let _ = coap_client.make_observe_request(
-            &format!("lights/{}", device_id),
-            true,
-            observe_callback,
-        );
+        let _ =
+            coap_client.make_observe_request(&uri, true, observe_callback, &mut |coap_client| {
+                if let Some(sntp_client) = sntp_client.as_mut() {
+                    sntp_client.poll();
+                }
+                if device.borrow_mut().poll(coap_client, &device_id) {
+                    device.borrow().report(coap_client, &device_id);
+                }
+                handle_button_event(coap_client, &device, &device_id);
+                updater.poll(coap_client, &uri);
+                Ok(())
+            });
         reconnect_if_needed(&mut controller);
     }
 }
 
+#[cfg(feature = "light")]
+fn init_device(peripherals: DevicePeripherals<'static>, fs: &mut FlashStorage) -> LightDevice {
+    let output = match get_rgb_env() {
+        Some(pins) => LightOutput::Rgb(RgbOutput::new(peripherals.ledc, pins)),
+        None => LightOutput::Dac(Dac::new(peripherals.dac2, peripherals.gpio26)),
+    };
+    LightDevice::new(output, fs)
+}
+
+#[cfg(feature = "relay")]
+fn init_device(_peripherals: DevicePeripherals<'static>, fs: &mut FlashStorage) -> Relay {
+    Relay::new(get_relay_env(), fs)
+}
+
+#[cfg(feature = "sensor")]
+fn init_device(peripherals: DevicePeripherals<'static>, _fs: &mut FlashStorage) -> Sensor {
+    Sensor::new(
+        get_sensor_env(),
+        peripherals.adc1,
+        peripherals.gpio34,
+        peripherals.i2c0,
+        peripherals.gpio21,
+        peripherals.gpio22,
+    )
+}
+
+fn handle_button_event<D: Device>(
+    coap_client: &mut CoapClient,
+    device: &RefCell<D>,
+    device_id: &str,
+) {
+    match button::take_event() {
+        Some(ButtonEvent::LongPress) => {
+            // The BLE connector is gone by now, so pairing has to start from a fresh boot
+            println!("Restarting into maintenance mode");
+            if let Err(err) = request_maintenance(&mut FlashStorage::new()) {
+                println!("{}", err);
+            }
+        }
+        Some(ButtonEvent::FactoryReset) => {
+            println!("Factory reset");
+            handle_device_reset(&mut FlashStorage::new(), ResetLevel::Factory);
+        }
+        Some(event) => {
+            if device.borrow_mut().on_button(event) {
+                device.borrow().report(coap_client, device_id);
+            }
+        }
+        None => {}
+    }
+}
+
 fn reconnect_if_needed(controller: &mut WifiController) {
     match controller.is_connected() {
         Ok(is_connected) => {
@@ -0,0 +1,326 @@
+//! Firmware updates from the server. The image is downloaded with CoAP Block2 into the
+//! OTA slot that isn't running, checked against the signed manifest and booted from after a reset.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use anyhow::anyhow;
+use core::fmt::Debug;
+use ed25519_compact::{PublicKey, Signature};
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::ota::{Ota, OtaImageState, Slot};
+use esp_bootloader_esp_idf::partitions::{
+    self, AppPartitionSubType, DataPartitionSubType, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use esp_hal::system::software_reset;
+use esp_println::println;
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::coap::{block_size, CoapClient};
+use crate::status_led::{self, Status};
+use crate::utils::{get_firmware_env, now};
+use crate::{SECURITY_VERSION_ADDR, SECURITY_VERSION_SIZE};
+
+const CHECK_INTERVAL_MS: u64 = 6 * 60 * 60 * 1000;
+// 1024 byte blocks, the largest Block2 allows and still within the socket's buffer
+const BLOCK_SZX: u8 = 6;
+const BLOCK_RETRIES: u8 = 3;
+const SECTOR_SIZE: u32 = 4096;
+// Progress is reported in steps of this many percent
+const PROGRESS_STEP: u32 = 10;
+
+/// What the server offers, fetched from `{resource}/firmware`
+#[derive(Deserialize)]
+struct Manifest {
+    version: String,
+    /// Where the server serves the image
+    path: String,
+    size: u32,
+    /// Hex encoded SHA-256 of the image
+    sha256: String,
+    /// Raised for security fixes, images below the stored one are refused
+    #[serde(default)]
+    security_version: u32,
+    /// Hex encoded Ed25519 signature of the hash followed by the little endian security version,
+    /// made with `utility-scripts/ota_image.py`
+    signature: String,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum Progress<'a> {
+    Downloading { version: &'a str, percent: u32 },
+    Installed { version: &'a str },
+    Failed { version: &'a str, error: String },
+}
+
+#[derive(Default)]
+pub struct Updater {
+    checked_at: Option<u64>,
+}
+
+impl Updater {
+    /// Checks for a new version at boot and every few hours, installing it resets the device
+    pub fn poll(&mut self, coap_client: &mut CoapClient, resource_path: &str) {
+        let now = now();
+        if self
+            .checked_at
+            .is_some_and(|checked_at| now - checked_at < CHECK_INTERVAL_MS)
+        {
+            return;
+        }
+        self.checked_at = Some(now);
+        let Some(manifest) = fetch_manifest(coap_client, resource_path) else {
+            return;
+        };
+        if manifest.version == running_version() {
+            return;
+        }
+        println!(
+            "Updating from {} to {}",
+            running_version(),
+            manifest.version
+        );
+        status_led::set(Status::OtaInProgress);
+        match install(coap_client, resource_path, &manifest) {
+            Ok(()) => {
+                report(
+                    coap_client,
+                    resource_path,
+                    &Progress::Installed {
+                        version: &manifest.version,
+                    },
+                );
+                println!("Update installed, restarting");
+                software_reset();
+            }
+            Err(err) => {
+                println!("Update failed: {}", err);
+                report(
+                    coap_client,
+                    resource_path,
+                    &Progress::Failed {
+                        version: &manifest.version,
+                        error: format!("{}", err),
+                    },
+                );
+                status_led::set(Status::Idle);
+            }
+        }
+    }
+}
+
+pub fn running_version() -> &'static str {
+    crate::ESP_APP_DESC.version()
+}
+
+/// Tells the bootloader the running image works, called once the server was reached.
+/// From then on nothing below its security version is installed.
+pub fn mark_valid() {
+    let mut flash = FlashStorage::new();
+    let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+    let result = with_ota(&mut flash, &mut buffer, |ota| {
+        match ota.current_ota_state() {
+            Ok(OtaImageState::New | OtaImageState::PendingVerify) => ota
+                .set_current_ota_state(OtaImageState::Valid)
+                .map(|()| true),
+            _ => Ok(false),
+        }
+    });
+    match result {
+        Ok(true) => println!("Running image marked valid"),
+        Ok(false) => {}
+        Err(err) => println!("Could not check the OTA state: {}", err),
+    }
+    let (_, security_version) = get_firmware_env();
+    let mut fs = FlashStorage::new();
+    if security_version > stored_security_version(&mut fs) {
+        let bytes = security_version.to_le_bytes();
+        match fs.write(SECURITY_VERSION_ADDR, &bytes) {
+            Ok(()) => println!("Security version raised to {}", security_version),
+            Err(err) => println!("Could not store the security version: {:?}", err),
+        }
+    }
+}
+
+/// 0 until a build with a security version was kept
+fn stored_security_version(fs: &mut FlashStorage) -> u32 {
+    let mut bytes = [0xffu8; SECURITY_VERSION_SIZE as usize];
+    if fs.read(SECURITY_VERSION_ADDR, &mut bytes).is_err()
+        || bytes == [0xff; SECURITY_VERSION_SIZE as usize]
+    {
+        return 0;
+    }
+    u32::from_le_bytes(bytes)
+}
+
+fn fetch_manifest(coap_client: &mut CoapClient, resource_path: &str) -> Option<Manifest> {
+    let resp = coap_client
+        .fetch(&format!("{}/firmware", resource_path))
+        .map_err(|err| println!("Could not check for updates: {}", err))
+        .ok()?;
+    // Not found when there is nothing to offer
+    serde_json::from_slice(&resp.payload).ok()
+}
+
+fn install(
+    coap_client: &mut CoapClient,
+    resource_path: &str,
+    manifest: &Manifest,
+) -> Result<(), anyhow::Error> {
+    let (public_key, _) = get_firmware_env();
+    let public_key = PublicKey::new(public_key.ok_or(anyhow!("No firmware key built in"))?);
+    let expected_hash = decode_hex(&manifest.sha256).ok_or(anyhow!("Invalid image hash"))?;
+    let signature = decode_hex(&manifest.signature)
+        .and_then(|signature| Signature::from_slice(&signature).ok())
+        .ok_or(anyhow!("Invalid image signature"))?;
+    let mut flash = FlashStorage::new();
+    let minimum = stored_security_version(&mut flash);
+    if manifest.security_version < minimum {
+        return Err(anyhow!(
+            "Security version {} is below {}",
+            manifest.security_version,
+            minimum
+        ));
+    }
+    let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+    let target = with_ota(&mut flash, &mut buffer, |ota| {
+        // Without OTA data the first slot is running
+        ota.current_slot().map(|slot| match slot {
+            Slot::Slot1 => Slot::Slot0,
+            _ => Slot::Slot1,
+        })
+    })?;
+    let sub_type = match target {
+        Slot::Slot0 => AppPartitionSubType::Ota0,
+        _ => AppPartitionSubType::Ota1,
+    };
+    let mut table_flash = FlashStorage::new();
+    let table = partitions::read_partition_table(&mut table_flash, &mut buffer)
+        .map_err(|err| anyhow!("Could not read the partition table: {:?}", err))?;
+    let partition = table
+        .find_partition(PartitionType::App(sub_type))
+        .map_err(|err| anyhow!("{:?}", err))?
+        .ok_or(anyhow!(
+            "No {:?} partition, flash with partitions.csv",
+            sub_type
+        ))?;
+    let mut region = partition.as_embedded_storage(&mut flash);
+    if manifest.size == 0 || manifest.size > region.capacity() as u32 {
+        return Err(anyhow!("Image doesn't fit the partition"));
+    }
+    region
+        .erase(0, manifest.size.div_ceil(SECTOR_SIZE) * SECTOR_SIZE)
+        .map_err(|err| anyhow!("Could not erase the partition: {:?}", err))?;
+
+    let uri = manifest.path.trim_start_matches('/');
+    let mut hasher = Sha256::new();
+    let mut received = 0u32;
+    let mut reported = 0;
+    let mut szx = BLOCK_SZX;
+    loop {
+        let num = received / block_size(szx);
+        let (mut block, more, block_szx) = fetch_block(coap_client, uri, num, szx)?;
+        // A server with smaller blocks gets asked in its size from now on
+        szx = block_szx;
+        let len = block.len() as u32;
+        if (more && len != block_size(szx)) || received + len > manifest.size {
+            return Err(anyhow!("Image doesn't match the announced size"));
+        }
+        hasher.update(&block);
+        // Flash is written in words, erased flash reads as 0xFF
+        block.resize(block.len().next_multiple_of(4), 0xFF);
+        region
+            .write(received, &block)
+            .map_err(|err| anyhow!("Could not write the image: {:?}", err))?;
+        received += len;
+        let percent = received * 100 / manifest.size;
+        if percent >= reported + PROGRESS_STEP {
+            reported = percent - percent % PROGRESS_STEP;
+            report(
+                coap_client,
+                resource_path,
+                &Progress::Downloading {
+                    version: &manifest.version,
+                    percent: reported,
+                },
+            );
+        }
+        if !more {
+            break;
+        }
+    }
+    if received != manifest.size {
+        return Err(anyhow!("Got {} bytes of {}", received, manifest.size));
+    }
+    let hash = hasher.finalize();
+    if hash.as_slice() != expected_hash.as_slice() {
+        return Err(anyhow!("Image hash doesn't match"));
+    }
+    // Covers the security version as well, so an old image can't be offered as a new one
+    let mut message = hash.to_vec();
+    message.extend_from_slice(&manifest.security_version.to_le_bytes());
+    public_key
+        .verify(&message, &signature)
+        .map_err(|_| anyhow!("Image signature doesn't match"))?;
+    let mut flash = FlashStorage::new();
+    with_ota(&mut flash, &mut buffer, |ota| {
+        ota.set_current_slot(target)
+            .and_then(|()| ota.set_current_ota_state(OtaImageState::New))
+    })
+}
+
+fn fetch_block(
+    coap_client: &mut CoapClient,
+    uri: &str,
+    num: u32,
+    szx: u8,
+) -> Result<(Vec<u8>, bool, u8), anyhow::Error> {
+    let mut attempt = 0;
+    loop {
+        match coap_client.fetch_block(uri, num, szx) {
+            Ok(block) => return Ok(block),
+            Err(err) if attempt + 1 == BLOCK_RETRIES => return Err(err),
+            Err(_) => attempt += 1,
+        }
+    }
+}
+
+/// Runs `f` on the OTA data partition
+fn with_ota<T, E: Debug>(
+    flash: &mut FlashStorage,
+    buffer: &mut [u8; PARTITION_TABLE_MAX_LEN],
+    f: impl FnOnce(&mut Ota<'_, FlashStorage>) -> Result<T, E>,
+) -> Result<T, anyhow::Error> {
+    let mut table_flash = FlashStorage::new();
+    let table = partitions::read_partition_table(&mut table_flash, buffer)
+        .map_err(|err| anyhow!("Could not read the partition table: {:?}", err))?;
+    let partition = table
+        .find_partition(PartitionType::Data(DataPartitionSubType::Ota))
+        .map_err(|err| anyhow!("{:?}", err))?
+        .ok_or(anyhow!("No OTA data partition, flash with partitions.csv"))?;
+    let mut region = partition.as_embedded_storage(flash);
+    let mut ota = Ota::new(&mut region).map_err(|err| anyhow!("{:?}", err))?;
+    f(&mut ota).map_err(|err| anyhow!("{:?}", err))
+}
+
+fn report(coap_client: &mut CoapClient, resource_path: &str, progress: &Progress) {
+    let payload = serde_json::to_vec(progress).unwrap();
+    if let Err(err) =
+        coap_client.make_put_request(&format!("{}/firmware/progress", resource_path), payload)
+    {
+        println!("Could not report the update progress: {}", err);
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -1,18 +1,24 @@
+#[cfg(feature = "improv")]
+use core::cell::RefCell;
 use core::{
     cell::Cell,
     cmp::max,
     ops::{Add, Sub},
+    str,
 };
 
+use alloc::format;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use bleps::{
     ad_structure::{
         create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
     },
-    att::Uuid,
+    att::{AttErrorCode, Uuid},
+    attribute::AttData,
     attribute_server::{AttributeServer, NotificationData, WorkResult},
-    gatt, Ble, HciConnector,
+    gatt, Addr, AdvertisingParameters, Ble, HciConnector,
 };
 use blocking_network_stack::Stack;
 use embedded_io::Write;
@@ -20,29 +26,121 @@ use embedded_storage::Storage;
 use esp_backtrace as _;
 use esp_println::println;
 // use embedded_io::blocking::Write;
-use crate::wifi_utils::connect_to_wifi;
+use crate::ble_security::{check_passkey, BleRng, PasskeyCheck};
+#[cfg(feature = "improv")]
+use crate::button;
+use crate::coap::CoapClient;
+use crate::device::Device;
+#[cfg(feature = "improv")]
+use crate::improv::{
+    ImprovCharacteristic, ImprovError, ImprovService, ImprovState, IMPROV_CAPABILITIES,
+    IMPROV_SERVICE_DATA_UUID, IMPROV_SERVICE_UUID_BYTES,
+};
+use crate::pairing_status::PairingStatus;
+use crate::status_led::{self, Status};
+use crate::wifi_utils::{connect_to_wifi, read_raw_wifi_credentials, store_wifi_credentials};
 use crate::{
-    utils::{get_device_id, get_device_secret},
-    CONFIG_ADDR, PASS_ADDR, SSID_ADDR,
+    utils::{
+        clear_server_config, get_device_id, get_device_id_string, get_device_secret,
+        get_server_config, now, set_device_configured, store_server_config,
+    },
+    PASS_ADDR, SSID_ADDR,
 };
+use esp_hal::delay::Delay;
+use esp_hal::rng::Rng;
 use esp_storage::FlashStorage;
 use esp_wifi::wifi::WifiDevice;
 use esp_wifi::{ble::controller::BleConnector, wifi::WifiController};
+use smoltcp::wire::{IpAddress, Ipv4Address};
+
+// 937312e0-2354-11eb-9f10-fbc30a62cf38, the pairing service below, in little endian
+const PAIRING_SERVICE_UUID_BYTES: [u8; 16] = [
+    0x38, 0xcf, 0x62, 0x0a, 0xc3, 0xfb, 0x10, 0x9f, 0xeb, 0x11, 0x54, 0x23, 0xe0, 0x12, 0x73, 0x93,
+];
+// Company identifier the Bluetooth SIG reserves for internal use
+const MANUFACTURER_ID: u16 = 0xffff;
+const BLE_POLL_INTERVAL_MS: u32 = 10;
+
+/// How pairing presents the device and where it asks the server about it,
+/// taken from the profile the firmware is built for
+pub struct PairingIdentity {
+    pub resource_path: String,
+    pub name: &'static str,
+    pub device_type: u8,
+}
+
+impl PairingIdentity {
+    pub fn new<D: Device>(device: &D, device_id: &str) -> Self {
+        Self {
+            resource_path: device.resource_path(device_id),
+            name: device.name(),
+            device_type: device.device_type(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum PairingMode {
+    /// First setup of an unconfigured device
+    Pairing = 0x00,
+    /// The owner updates Wi-Fi or server settings of a configured device,
+    /// which keeps its identity and light state
+    Maintenance = 0x01,
+}
 
 #[allow(non_snake_case)]
+#[allow(clippy::too_many_arguments)]
 pub fn init_advertising<'a>(
     hci: &HciConnector<BleConnector<'a>>,
     controller: &mut WifiController,
     wifi_stack: &Stack<WifiDevice>,
+    coap_client: &mut CoapClient,
+    identity: &PairingIdentity,
+    rng: Rng,
+    mode: PairingMode,
+    timeout_ms: u64,
+    advertising_interval_ms: u16,
 ) -> bool
 where
 {
     let mut fs = FlashStorage::new();
+    let device_id = get_device_id_string(&mut fs);
+    let mut deadline = now() + timeout_ms;
+    // Settings that worked before, put back if maintenance doesn't end with working ones
+    let previous_settings = (mode == PairingMode::Maintenance).then(|| {
+        (
+            read_raw_wifi_credentials(&mut fs),
+            get_server_config(&mut fs),
+        )
+    });
 
     let mut ble = Ble::new(hci);
-    init_bluetooth(&mut ble);
+    let device_name = advertised_name(identity.name, &device_id);
+    init_bluetooth(
+        &mut ble,
+        &device_name,
+        identity.device_type,
+        mode,
+        advertising_interval_ms,
+    );
+    status_led::set(Status::Pairing);
     println!("Started advertising");
 
+    // Sensitive characteristics are only served over an encrypted link
+    // to a phone that also proved it knows the passkey from the label
+    let is_link_encrypted = Cell::new(false);
+    let is_passkey_verified = Cell::new(false);
+    // The error tells the phone to pair or to send the passkey first
+    let check_access = || {
+        if !is_link_encrypted.get() {
+            Err(AttErrorCode::InsufficientEncryption)
+        } else if !is_passkey_verified.get() {
+            Err(AttErrorCode::InsufficientAuthentication)
+        } else {
+            Ok(())
+        }
+    };
+
     let mut read_id = |offset: usize, mut data: &mut [u8]| {
         let mut fs = FlashStorage::new();
         let id_bytes = get_device_id(&mut fs);
@@ -56,108 +154,401 @@ where
     let mut ssid_message_started = false;
     let is_ssid_written = Cell::new(false);
     let is_password_written = Cell::new(false);
+    let is_server_written = Cell::new(false);
     let mut ssid_suffix_bytes = 0u8;
-    let mut write_wifi_ssid = |_offset: usize, data: &[u8]| {
-        handle_write(
-            &mut ssid_buf,
-            &mut ssid_message_started,
-            SSID_ADDR,
-            &mut ssid_offset,
-            data,
-            &is_ssid_written,
-            &mut ssid_suffix_bytes,
-        )
-    };
+    let mut wifi_ssid =
+        Guarded::write_only(|_offset: usize, data: &[u8]| -> Result<(), AttErrorCode> {
+            if let Err(error) = check_access() {
+                println!("Rejected SSID write on unauthenticated link");
+                return Err(error);
+            }
+            handle_write(
+                &mut ssid_buf,
+                &mut ssid_message_started,
+                SSID_ADDR,
+                &mut ssid_offset,
+                data,
+                &is_ssid_written,
+                &mut ssid_suffix_bytes,
+            );
+            Ok(())
+        });
     let mut pass_buf: [u8; 128] = [0u8; 128];
     let mut pass_offset: usize = 0;
     let mut pass_message_started = false;
     let mut pass_suffix_bytes = 0u8;
 
-    let mut write_wifi_password = |_offset: usize, data: &[u8]| {
-        handle_write(
-            &mut pass_buf,
-            &mut pass_message_started,
-            PASS_ADDR,
-            &mut pass_offset,
-            data,
-            &is_password_written,
-            &mut pass_suffix_bytes,
-        )
-    };
+    let mut wifi_password =
+        Guarded::write_only(|_offset: usize, data: &[u8]| -> Result<(), AttErrorCode> {
+            if let Err(error) = check_access() {
+                println!("Rejected password write on unauthenticated link");
+                return Err(error);
+            }
+            handle_write(
+                &mut pass_buf,
+                &mut pass_message_started,
+                PASS_ADDR,
+                &mut pass_offset,
+                data,
+                &is_password_written,
+                &mut pass_suffix_bytes,
+            );
+            Ok(())
+        });
 
-    let mut read_secret = |offset: usize, mut data: &mut [u8]| {
-        let secret = get_device_secret(&mut fs);
-        data.write(&secret[offset..]).unwrap();
-        344 - offset
-    };
-    let mut notify_configured_read = |offset: usize, mut data: &mut [u8]| {
-        // let secret = get_device_secret(&mut fs);
-        // data.write(&secret).unwrap();
-        let mut buf = b"false\0\0\0";
-        if false {
-            buf = b"true\0\0\0\0";
-        }
-        data.write(buf).unwrap();
-        8 - offset
+    let mut device_secret = Guarded::read_only(
+        |offset: usize, mut data: &mut [u8]| -> Result<usize, AttErrorCode> {
+            check_access()?;
+            let mut fs = FlashStorage::new();
+            let secret = get_device_secret(&mut fs);
+            data.write(&secret[offset..]).unwrap();
+            Ok(344 - offset)
+        },
+    );
+    let status = Cell::new(PairingStatus::Idle);
+    let notify_configured_read =
+        |offset: usize, mut data: &mut [u8]| -> Result<usize, AttErrorCode> {
+            let mut buf = b"false\0\0\0";
+            if matches!(
+                status.get(),
+                PairingStatus::ServerReachable | PairingStatus::Done
+            ) {
+                buf = b"true\0\0\0\0";
+            }
+            data.write(&buf[offset..]).unwrap();
+            Ok(8 - offset)
+        };
+    let mut status_read = |offset: usize, mut data: &mut [u8]| {
+        let status_bytes = status.get().to_bytes();
+        data.write(&status_bytes[offset..]).unwrap();
+        status_bytes.len() - offset
     };
+    let mut pairing_passkey =
+        Guarded::write_only(|_offset: usize, data: &[u8]| -> Result<(), AttErrorCode> {
+            // Never sent in the clear
+            if !is_link_encrypted.get() {
+                println!("Rejected passkey write on unencrypted link");
+                return Err(AttErrorCode::InsufficientEncryption);
+            }
+            let mut fs = FlashStorage::new();
+            let check = check_passkey(&mut fs, data);
+            if check == PasskeyCheck::LockedOut {
+                println!("Too many wrong passkeys, restart the device to try again");
+            }
+            is_passkey_verified.set(check == PasskeyCheck::Valid);
+            Ok(())
+        });
+    let new_server = Cell::new(None);
+    let mut server_address =
+        Guarded::write_only(|_offset: usize, data: &[u8]| -> Result<(), AttErrorCode> {
+            if let Err(error) = check_access() {
+                println!("Rejected server write on unauthenticated link");
+                return Err(error);
+            }
+            match parse_server_address(data) {
+                Some(server) => new_server.set(Some(server)),
+                None => println!("Invalid server address"),
+            }
+            Ok(())
+        });
     let is_config_conifrmed = Cell::new(false);
-    let mut notify_configured_write = |_offset: usize, _data: &[u8]| {
+    let notify_configured_write = |_offset: usize, _data: &[u8]| -> Result<(), AttErrorCode> {
+        if let Err(error) = check_access() {
+            println!("Rejected configuration confirmation on unauthenticated link");
+            return Err(error);
+        }
         is_config_conifrmed.set(true);
+        Ok(())
     };
-    gatt!([service {
-        uuid: "937312e0-2354-11eb-9f10-fbc30a62cf38",
-        characteristics: [
-            characteristic {
-                name: "Device_Id",
-                uuid: "2137",
-                read: read_id,
-            },
-            characteristic {
-                name: "Device_Secret",
-                uuid: "987312e0-2354-11eb-9f10-fbc30a62cf38",
-                read: read_secret,
-            },
-            characteristic {
-                name: "device_configured",
-                uuid: "987312e0-2354-11eb-9f10-fbc30a62cf50",
-                notify: true,
-                read: notify_configured_read,
-                write: notify_configured_write,
-            },
-            characteristic {
-                uuid: "937312e0-2354-11eb-9f10-fbc30a62cf39",
-                name: "WiFi_SSID",
-                write: write_wifi_ssid,
-            },
-            characteristic {
-                name: "WiFi_Password",
-                uuid: "987312e0-2354-11eb-9f10-fbc30a62cf40",
-                write: write_wifi_password,
-            },
-        ],
-    },]);
+    let mut device_configured = Guarded {
+        read: Some(notify_configured_read),
+        write: Some(notify_configured_write),
+    };
+
+    #[cfg(feature = "improv")]
+    let improv = RefCell::new(ImprovService::new());
+    #[cfg(feature = "improv")]
+    let mut improv_state_read = |offset: usize, data: &mut [u8]| {
+        single_byte_read(offset, data, improv.borrow().state as u8)
+    };
+    #[cfg(feature = "improv")]
+    let mut improv_error_read = |offset: usize, data: &mut [u8]| {
+        single_byte_read(offset, data, improv.borrow().error as u8)
+    };
+    #[cfg(feature = "improv")]
+    let mut improv_rpc_write = |_offset: usize, data: &[u8]| {
+        improv
+            .borrow_mut()
+            .handle_rpc_write(data, device_name.as_bytes());
+    };
+    #[cfg(feature = "improv")]
+    let mut improv_rpc_result_read = |offset: usize, mut data: &mut [u8]| {
+        let improv = improv.borrow();
+        let result = improv.rpc_result.get(offset..).unwrap_or(&[]);
+        data.write(result).unwrap()
+    };
+    #[cfg(feature = "improv")]
+    let mut improv_capabilities_read =
+        |offset: usize, data: &mut [u8]| single_byte_read(offset, data, IMPROV_CAPABILITIES);
+
+    // gatt! takes every service at once, so the pairing service is written out here and
+    // the Improv service is passed in when that feature is on. Names gatt! declares
+    // aren't visible outside the macro, the ones used later are passed in
+    macro_rules! pairing_gatt {
+        (
+            $attributes:ident,
+            $device_configured_handle:ident,
+            $pairing_status_handle:ident,
+            $improv_handles:ident,
+            [$($extra_service:tt)*]
+        ) => {
+            gatt!([
+                service {
+                    uuid: "937312e0-2354-11eb-9f10-fbc30a62cf38",
+                    characteristics: [
+                        characteristic {
+                            name: "Device_Id",
+                            uuid: "2137",
+                            read: read_id,
+                        },
+                        characteristic {
+                            name: "Device_Secret",
+                            uuid: "987312e0-2354-11eb-9f10-fbc30a62cf38",
+                            data: device_secret,
+                        },
+                        characteristic {
+                            name: "device_configured",
+                            uuid: "987312e0-2354-11eb-9f10-fbc30a62cf50",
+                            notify: true,
+                            data: device_configured,
+                        },
+                        characteristic {
+                            uuid: "937312e0-2354-11eb-9f10-fbc30a62cf39",
+                            name: "WiFi_SSID",
+                            data: wifi_ssid,
+                        },
+                        characteristic {
+                            name: "WiFi_Password",
+                            uuid: "987312e0-2354-11eb-9f10-fbc30a62cf40",
+                            data: wifi_password,
+                        },
+                        characteristic {
+                            name: "Pairing_Passkey",
+                            uuid: "987312e0-2354-11eb-9f10-fbc30a62cf41",
+                            data: pairing_passkey,
+                        },
+                        characteristic {
+                            name: "Server_Address",
+                            uuid: "987312e0-2354-11eb-9f10-fbc30a62cf43",
+                            data: server_address,
+                        },
+                        characteristic {
+                            name: "pairing_status",
+                            uuid: "987312e0-2354-11eb-9f10-fbc30a62cf42",
+                            notify: true,
+                            read: status_read,
+                        },
+                    ],
+                },
+                $($extra_service)*
+            ]);
+            let $attributes = &mut gatt_attributes;
+            let $device_configured_handle = device_configured_handle;
+            let $pairing_status_handle = pairing_status_handle;
+            #[cfg(feature = "improv")]
+            let $improv_handles = (
+                improv_state_handle,
+                improv_error_handle,
+                improv_rpc_result_handle,
+            );
+        };
+    }
+    #[cfg(not(feature = "improv"))]
+    pairing_gatt!(
+        gatt_attributes,
+        device_configured_handle,
+        pairing_status_handle,
+        improv_handles,
+        []
+    );
+    #[cfg(feature = "improv")]
+    pairing_gatt!(
+        gatt_attributes,
+        device_configured_handle,
+        pairing_status_handle,
+        improv_handles,
+        [service {
+            uuid: "00467768-6228-2272-4663-277478268000",
+            characteristics: [
+                characteristic {
+                    name: "improv_state",
+                    uuid: "00467768-6228-2272-4663-277478268001",
+                    notify: true,
+                    read: improv_state_read,
+                },
+                characteristic {
+                    name: "improv_error",
+                    uuid: "00467768-6228-2272-4663-277478268002",
+                    notify: true,
+                    read: improv_error_read,
+                },
+                characteristic {
+                    name: "improv_rpc_command",
+                    uuid: "00467768-6228-2272-4663-277478268003",
+                    write: improv_rpc_write,
+                },
+                characteristic {
+                    name: "improv_rpc_result",
+                    uuid: "00467768-6228-2272-4663-277478268004",
+                    notify: true,
+                    read: improv_rpc_result_read,
+                },
+                characteristic {
+                    name: "improv_capabilities",
+                    uuid: "00467768-6228-2272-4663-277478268005",
+                    read: improv_capabilities_read,
+                },
+            ],
+        },]
+    );
+    #[cfg(feature = "improv")]
+    let (improv_state_handle, improv_error_handle, improv_rpc_result_handle) = improv_handles;
 
-    let mut rng = bleps::no_rng::NoRng;
-    let mut srv = AttributeServer::new(&mut ble, &mut gatt_attributes, &mut rng);
+    let mut rng = BleRng(rng);
+    let local_addr = Addr::from_le_bytes(false, ble.cmd_read_br_addr().unwrap());
+    // No bond is restored, bleps doesn't tell whether a reconnecting phone encrypted
+    // the link with it, so every connection pairs again
+    let mut srv =
+        AttributeServer::new_with_ltk(&mut ble, gatt_attributes, local_addr, None, &mut rng);
+    // bleps only offers numeric comparison, which needs a display to confirm the code.
+    // Without one the pairing itself is unauthenticated, the label passkey is checked
+    // by the Pairing_Passkey characteristic once the link is encrypted
+    let mut pin_callback = |_pin: u32| {};
+    srv.set_pin_callback(Some(&mut pin_callback));
+    // Key of a pairing on an earlier connection, it says nothing about the current one
+    let mut previous_ltk = None;
     let mut is_connection_succesful = None;
-    loop {
-        let mut notification_data = None;
-        // unwrap is safe because we just assigned the value
-        if is_password_written.get() && is_ssid_written.get() && is_connection_succesful.is_none() {
-            // ble.get_mut().cmd_set_le_advertise_enable(false);
-            is_connection_succesful = Some(connect_to_wifi(controller, wifi_stack));
-            if is_connection_succesful.unwrap() {
-                println!("Notifying the app");
-                notification_data = Some(NotificationData::new(
-                    device_configured_handle,
-                    b"true\0\0\0\0",
-                ));
+    let mut notifications: Vec<(u16, Vec<u8>)> = vec![];
+    let set_status = |notifications: &mut Vec<(u16, Vec<u8>)>, new_status: PairingStatus| {
+        println!("Pairing status: {:?}", new_status);
+        status.set(new_status);
+        notifications.push((pairing_status_handle, new_status.to_bytes().to_vec()));
+    };
+    let delay = Delay::new();
+    let is_paired = loop {
+        // A new long term key means LE Secure Connections pairing finished on this connection
+        let ltk = srv.get_ltk();
+        if ltk.is_some() && ltk != previous_ltk {
+            previous_ltk = ltk;
+            is_link_encrypted.set(true);
+        }
+        if let Some((ip_address, port)) = new_server.take() {
+            match store_server_config(&mut fs, ip_address, port) {
+                Ok(()) => {
+                    coap_client.set_server(ip_address, port);
+                    is_server_written.set(true);
+                }
+                Err(err) => println!("{}", err),
+            }
+        }
+        // In maintenance a new server alone is enough, the stored credentials are used to reach it
+        let is_settings_written = (is_password_written.get() && is_ssid_written.get())
+            || (mode == PairingMode::Maintenance && is_server_written.get());
+        if is_settings_written && is_connection_succesful.is_none() {
+            match status.get() {
+                // Connecting and verifying block, so they only start once the app heard about the previous step
+                PairingStatus::Connecting if notifications.is_empty() => {
+                    // ble.get_mut().cmd_set_le_advertise_enable(false);
+                    match connect_to_wifi(controller, wifi_stack) {
+                        Ok(()) => {
+                            // Connecting took over the LED, we're still pairing though
+                            status_led::set(Status::Pairing);
+                            set_status(&mut notifications, PairingStatus::GotIp)
+                        }
+                        Err(err) => {
+                            set_status(&mut notifications, PairingStatus::Failed(err.into()));
+                            is_connection_succesful = Some(false);
+                        }
+                    }
+                    deadline = now() + timeout_ms;
+                }
+                PairingStatus::GotIp if notifications.is_empty() => {
+                    match coap_client
+                        .verify_device(&identity.resource_path, &get_device_secret(&mut fs))
+                    {
+                        Ok(()) => {
+                            set_status(&mut notifications, PairingStatus::ServerReachable);
+                            println!("Notifying the app");
+                            notifications
+                                .push((device_configured_handle, b"true\0\0\0\0".to_vec()));
+                            is_connection_succesful = Some(true);
+                        }
+                        Err(err) => {
+                            set_status(&mut notifications, PairingStatus::Failed(err.into()));
+                            is_connection_succesful = Some(false);
+                        }
+                    }
+                    // Give the app time to confirm or send new credentials
+                    deadline = now() + timeout_ms;
+                }
+                PairingStatus::Connecting | PairingStatus::GotIp => {}
+                _ => {
+                    set_status(&mut notifications, PairingStatus::CredentialsReceived);
+                    set_status(&mut notifications, PairingStatus::Connecting);
+                }
+            }
+        }
+        #[cfg(feature = "improv")]
+        {
+            let mut improv_service = improv.borrow_mut();
+            if button::is_pressed() {
+                improv_service.authorize(now());
+            }
+            improv_service.check_authorization_timeout(now());
+            while let Some((characteristic, value)) = improv_service.take_notification() {
+                let handle = match characteristic {
+                    ImprovCharacteristic::State => improv_state_handle,
+                    ImprovCharacteristic::Error => improv_error_handle,
+                    ImprovCharacteristic::RpcResult => improv_rpc_result_handle,
+                };
+                notifications.push((handle, value));
+            }
+            // Connecting blocks, so the client has to hear about it before we start
+            if notifications.is_empty() {
+                if improv_service.state == ImprovState::Provisioned {
+                    set_device_configured(&mut fs);
+                    break true;
+                }
+                if let Some((ssid, password)) = improv_service.take_wifi_settings() {
+                    if !store_wifi_credentials(&mut fs, &ssid, &password)
+                        || connect_to_wifi(controller, wifi_stack).is_err()
+                    {
+                        improv_service.provisioning_failed(ImprovError::UnableToConnect);
+                    } else if coap_client
+                        .verify_device(&identity.resource_path, &get_device_secret(&mut fs))
+                        .is_err()
+                    {
+                        improv_service.provisioning_failed(ImprovError::Unknown);
+                    } else {
+                        improv_service.provisioned();
+                    }
+                }
             }
         }
+        let notification = if notifications.is_empty() {
+            None
+        } else {
+            Some(notifications.remove(0))
+        };
+        let notification_data = notification
+            .as_ref()
+            .map(|(handle, value)| NotificationData::new(*handle, value));
         match srv.do_work_with_notification(notification_data) {
             Ok(x) => {
                 if x == WorkResult::GotDisconnected {
-                    // break;
+                    // Every new connection has to pair and enter the passkey again
+                    is_link_encrypted.set(false);
+                    is_passkey_verified.set(false);
                 }
             }
             Err(e) => {
@@ -166,35 +557,125 @@ where
         };
         if let Some(connected) = is_connection_succesful {
             if connected {
-                if is_config_conifrmed.get() {
-                    let config_bytes = [0u8; 4];
-                    fs.write(CONFIG_ADDR, &config_bytes).unwrap();
-
-                    return true;
+                if is_config_conifrmed.get() && status.get() != PairingStatus::Done {
+                    set_device_configured(&mut fs);
+                    set_status(&mut notifications, PairingStatus::Done);
+                }
+                // Only leave once the app got the final status
+                if status.get() == PairingStatus::Done && notifications.is_empty() {
+                    break true;
                 }
             } else {
                 is_config_conifrmed.set(false);
                 is_ssid_written.set(false);
                 is_password_written.set(false);
+                is_server_written.set(false);
+                // Wait for the app to send the credentials again
+                is_connection_succesful = None;
+                status_led::set(Status::Pairing);
             }
         }
 
-        // let delay = delay::Delay::new(clocks);
+        if now() > deadline {
+            break false;
+        }
 
-        // Inside loop:
-        // delay.delay_millis(50u32);
+        // Don't spin while there is nothing to send, the controller queues incoming events
+        if notifications.is_empty() {
+            delay.delay_millis(BLE_POLL_INTERVAL_MS);
+        }
+    };
+    // Stop the server before turning off advertising, nothing else uses BLE
+    // afterwards and it only interferes with Wi-Fi
+    drop(srv);
+    ble.cmd_set_le_advertise_enable(false).unwrap();
+    println!("Stopped advertising");
+    if let (false, Some(((ssid, password), server))) = (is_paired, previous_settings) {
+        println!("Restoring previous settings");
+        store_wifi_credentials(&mut fs, &ssid, &password);
+        let restored = match server {
+            Some((ip_address, port)) => store_server_config(&mut fs, ip_address, port),
+            None => clear_server_config(&mut fs),
+        };
+        if let Err(err) = restored {
+            println!("{}", err);
+        }
     }
+    is_paired
+}
+
+#[cfg(not(feature = "improv"))]
+fn init_bluetooth(
+    ble: &mut Ble,
+    device_name: &str,
+    device_type: u8,
+    mode: PairingMode,
+    advertising_interval_ms: u16,
+) {
+    println!("Begin bluetooth stuff");
+    ble.init().unwrap();
+    set_advertising_interval(ble, advertising_interval_ms);
+    ble.cmd_set_le_advertising_data(
+        create_advertising_data(&[
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            AdStructure::ServiceUuids128(&[Uuid::Uuid128(PAIRING_SERVICE_UUID_BYTES)]),
+            AdStructure::ManufacturerSpecificData {
+                company_identifier: MANUFACTURER_ID,
+                payload: &manufacturer_data(device_type, mode),
+            },
+        ])
+        .unwrap(),
+    )
+    .unwrap();
+    // Advertising data is full with the 128 bit uuid, so the name is only in the scan response
+    ble.cmd_set_le_scan_rsp_data(
+        create_advertising_data(&[AdStructure::CompleteLocalName(device_name)]).unwrap(),
+    )
+    .unwrap();
+    ble.cmd_set_le_advertise_enable(true).unwrap();
 }
 
-fn init_bluetooth(ble: &mut Ble) {
+/// Improv clients look for their service uuid and state in the advertising data,
+/// so our pairing service uuid goes into the scan response. With it and the
+/// manufacturer data the scan response is full, the name doesn't fit anymore
+#[cfg(feature = "improv")]
+fn init_bluetooth(
+    ble: &mut Ble,
+    _device_name: &str,
+    device_type: u8,
+    mode: PairingMode,
+    advertising_interval_ms: u16,
+) {
     println!("Begin bluetooth stuff");
     ble.init().unwrap();
-    ble.cmd_set_le_advertising_parameters().unwrap();
+    set_advertising_interval(ble, advertising_interval_ms);
+    let service_data = [
+        ImprovState::AuthorizationRequired as u8,
+        IMPROV_CAPABILITIES,
+        0,
+        0,
+        0,
+        0,
+    ];
     ble.cmd_set_le_advertising_data(
         create_advertising_data(&[
             AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-            AdStructure::ServiceUuids16(&[Uuid::Uuid16(0x1809)]),
-            AdStructure::CompleteLocalName("Fancy lights"),
+            AdStructure::ServiceUuids128(&[Uuid::Uuid128(IMPROV_SERVICE_UUID_BYTES)]),
+            AdStructure::ServiceData16 {
+                uuid: IMPROV_SERVICE_DATA_UUID,
+                data: &service_data,
+            },
+        ])
+        .unwrap(),
+    )
+    .unwrap();
+    ble.cmd_set_le_scan_rsp_data(
+        create_advertising_data(&[
+            AdStructure::ServiceUuids128(&[Uuid::Uuid128(PAIRING_SERVICE_UUID_BYTES)]),
+            AdStructure::ManufacturerSpecificData {
+                company_identifier: MANUFACTURER_ID,
+                payload: &manufacturer_data(device_type, mode),
+            },
         ])
         .unwrap(),
     )
@@ -202,6 +683,108 @@ fn init_bluetooth(ble: &mut Ble) {
     ble.cmd_set_le_advertise_enable(true).unwrap();
 }
 
+/// Suffix from the device id, so several unpaired devices can be told apart
+fn advertised_name(name: &str, device_id: &str) -> String {
+    format!("{} {}", name, device_id[..4].to_uppercase())
+}
+
+/// Device type, firmware version and pairing mode, read by the app before connecting
+fn manufacturer_data(device_type: u8, mode: PairingMode) -> [u8; 5] {
+    [
+        device_type,
+        env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+        mode as u8,
+    ]
+}
+
+/// Server is written as ascii "ip:port", possibly zero padded
+fn parse_server_address(data: &[u8]) -> Option<(IpAddress, u16)> {
+    let address = str::from_utf8(data).ok()?.trim_matches(char::from(0));
+    let (ip_address, port) = address.split_once(':')?;
+    let ip_address = ip_address.parse::<Ipv4Address>().ok()?;
+    let port = port.parse::<u16>().ok()?;
+    Some((IpAddress::Ipv4(ip_address), port))
+}
+
+/// Longer intervals save power, shorter ones make the light show up faster in the app
+fn set_advertising_interval(ble: &mut Ble, advertising_interval_ms: u16) {
+    // Interval is set in units of 0.625 ms
+    let interval = (advertising_interval_ms as u32 * 8 / 5).clamp(0x20, 0x4000) as u16;
+    ble.cmd_set_le_advertising_parameters_custom(&AdvertisingParameters {
+        interval_min: interval,
+        interval_max: interval,
+        ..Default::default()
+    })
+    .unwrap();
+}
+
+type NoRead = fn(usize, &mut [u8]) -> Result<usize, AttErrorCode>;
+type NoWrite = fn(usize, &[u8]) -> Result<(), AttErrorCode>;
+
+/// Characteristic with handlers that can answer with an ATT error,
+/// the plain read and write closures of gatt! can't refuse a request
+struct Guarded<R, W> {
+    read: Option<R>,
+    write: Option<W>,
+}
+
+impl<R> Guarded<R, NoWrite> {
+    fn read_only(read: R) -> Self {
+        Guarded {
+            read: Some(read),
+            write: None,
+        }
+    }
+}
+
+impl<W> Guarded<NoRead, W> {
+    fn write_only(write: W) -> Self {
+        Guarded {
+            read: None,
+            write: Some(write),
+        }
+    }
+}
+
+impl<R, W> AttData for Guarded<R, W>
+where
+    R: FnMut(usize, &mut [u8]) -> Result<usize, AttErrorCode>,
+    W: FnMut(usize, &[u8]) -> Result<(), AttErrorCode>,
+{
+    fn readable(&self) -> bool {
+        self.read.is_some()
+    }
+
+    fn read(&mut self, offset: usize, data: &mut [u8]) -> Result<usize, AttErrorCode> {
+        match &mut self.read {
+            Some(read) => read(offset, data),
+            None => Err(AttErrorCode::ReadNotPermitted),
+        }
+    }
+
+    fn writable(&self) -> bool {
+        self.write.is_some()
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), AttErrorCode> {
+        match &mut self.write {
+            Some(write) => write(offset, data),
+            None => Err(AttErrorCode::WriteNotPermitted),
+        }
+    }
+}
+
+#[cfg(feature = "improv")]
+fn single_byte_read(offset: usize, data: &mut [u8], value: u8) -> usize {
+    if offset > 0 {
+        return 0;
+    }
+    data[0] = value;
+    1
+}
+
 fn handle_write(
     buf: &mut [u8],
     message_started: &mut bool,
@@ -0,0 +1,60 @@
+use crate::errors::{BackendError, WifiConnectError};
+
+/// Progress of provisioning as reported to the app through the Pairing_Status characteristic.
+/// Sent as two bytes, the state code and the failure reason (0 unless failed)
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PairingStatus {
+    Idle,
+    CredentialsReceived,
+    Connecting,
+    GotIp,
+    ServerReachable,
+    Done,
+    Failed(FailureReason),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FailureReason {
+    InvalidCredentials = 1,
+    NoAccessPoint = 2,
+    WrongPassword = 3,
+    DhcpTimeout = 4,
+    ServerUnreachable = 5,
+    DeviceUnknown = 6,
+    NotAuthenticated = 7,
+}
+
+impl PairingStatus {
+    pub fn to_bytes(self) -> [u8; 2] {
+        match self {
+            PairingStatus::Idle => [0, 0],
+            PairingStatus::CredentialsReceived => [1, 0],
+            PairingStatus::Connecting => [2, 0],
+            PairingStatus::GotIp => [3, 0],
+            PairingStatus::ServerReachable => [4, 0],
+            PairingStatus::Done => [5, 0],
+            PairingStatus::Failed(reason) => [0xff, reason as u8],
+        }
+    }
+}
+
+impl From<WifiConnectError> for FailureReason {
+    fn from(error: WifiConnectError) -> Self {
+        match error {
+            WifiConnectError::InvalidCredentials => FailureReason::InvalidCredentials,
+            WifiConnectError::NoAccessPoint => FailureReason::NoAccessPoint,
+            WifiConnectError::WrongPassword => FailureReason::WrongPassword,
+            WifiConnectError::DhcpTimeout => FailureReason::DhcpTimeout,
+        }
+    }
+}
+
+impl From<BackendError> for FailureReason {
+    fn from(error: BackendError) -> Self {
+        match error {
+            BackendError::Unreachable => FailureReason::ServerUnreachable,
+            BackendError::DeviceUnknown => FailureReason::DeviceUnknown,
+            BackendError::NotAuthenticated => FailureReason::NotAuthenticated,
+        }
+    }
+}
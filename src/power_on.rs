@@ -0,0 +1,135 @@
+//! Light state kept through a power cut, and what the light does when power comes back.
+//! Applied before networking, so a light on a wall switch works without the server.
+use esp_println::println;
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize};
+
+use crate::light::{self, LightState};
+use crate::scenes::SceneStore;
+use crate::utils::{now, read_alternating_record, write_alternating_record};
+use crate::LIGHT_STATE_ADDR;
+
+// Dragging a slider sends a burst of states, only the one it settles on is written
+const SETTLE_MS: u64 = 5 * 1000;
+// Every write erases a whole sector
+const MIN_WRITE_INTERVAL_MS: u64 = 2 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PowerOn {
+    #[default]
+    LastState,
+    On,
+    Off,
+    Scene {
+        scene: u8,
+    },
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedState {
+    #[serde(default)]
+    power_on: PowerOn,
+    #[serde(default)]
+    state: Option<LightState>,
+}
+
+pub struct StateKeeper {
+    saved: SavedState,
+    // Last state seen on the light and when it changed, waiting to be written
+    seen: Option<LightState>,
+    changed_at: Option<u64>,
+    written_at: Option<u64>,
+}
+
+impl StateKeeper {
+    pub fn load(fs: &mut FlashStorage) -> Self {
+        let saved: SavedState = read_alternating_record(fs, LIGHT_STATE_ADDR).unwrap_or_default();
+        Self {
+            seen: saved.state.clone(),
+            saved,
+            changed_at: None,
+            written_at: None,
+        }
+    }
+
+    pub fn power_on(&self) -> PowerOn {
+        self.saved.power_on
+    }
+
+    /// State to start with before the server has a say, `None` leaves the light off
+    pub fn power_on_state(&self, scenes: &SceneStore) -> Option<LightState> {
+        let last_state = self.saved.state.clone();
+        match self.saved.power_on {
+            PowerOn::LastState => last_state,
+            PowerOn::On | PowerOn::Off => {
+                let mut state = last_state.unwrap_or_else(light::state);
+                state.is_on = self.saved.power_on == PowerOn::On;
+                Some(state)
+            }
+            PowerOn::Scene { scene } => match scenes.find(scene) {
+                Some(scene) => Some(scene.to_state()),
+                None => {
+                    println!("Power on scene {} is gone, using the last state", scene);
+                    last_state
+                }
+            },
+        }
+    }
+
+    /// Rarely changes, so it is written right away
+    pub fn set_power_on(&mut self, power_on: PowerOn) {
+        if power_on == self.saved.power_on {
+            return;
+        }
+        self.saved.power_on = power_on;
+        self.write(now());
+    }
+
+    /// Writes the light's state once it settled, meant to be called from the main loop
+    pub fn poll(&mut self) {
+        let now = now();
+        let state = Some(settled(light::state()));
+        if state != self.seen {
+            self.seen = state;
+            self.changed_at = Some(now);
+        }
+        let Some(changed_at) = self.changed_at else {
+            return;
+        };
+        if now - changed_at < SETTLE_MS
+            || self
+                .written_at
+                .is_some_and(|written_at| now - written_at < MIN_WRITE_INTERVAL_MS)
+        {
+            return;
+        }
+        self.changed_at = None;
+        // It might have gone back to what is already stored
+        if self.seen != self.saved.state {
+            self.saved.state = self.seen.clone();
+            self.write(now);
+        }
+    }
+
+    fn write(&mut self, now: u64) {
+        self.written_at = Some(now);
+        if let Err(err) =
+            write_alternating_record(&mut FlashStorage::new(), LIGHT_STATE_ADDR, &self.saved)
+        {
+            println!("{}", err);
+        }
+    }
+}
+
+/// Only what the light looks like, without how it got there
+fn settled(state: LightState) -> LightState {
+    LightState {
+        removed: false,
+        transition_ms: 0,
+        scenes_version: None,
+        schedules_version: None,
+        power_on: None,
+        ..state
+    }
+}
@@ -0,0 +1,288 @@
+//! Mains relays, e.g. a smart plug or a multi-relay board, on the pins from the `RELAY_PINS` build env.
+//! Switching is held back to protect the contacts, and interlocked relays are never on together.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use esp_hal::gpio::{AnyPin, Level, Output, OutputConfig};
+use esp_println::println;
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize};
+
+use crate::button::ButtonEvent;
+use crate::coap::CoapClient;
+use crate::device::{Capabilities, Device};
+use crate::status_led;
+use crate::utils::{now, read_alternating_record, write_alternating_record};
+use crate::RELAY_STATE_ADDR;
+
+// Time between switching an interlocked relay off and another one on, so they never overlap
+const INTERLOCK_DEAD_TIME_MS: u64 = 100;
+// Every write erases a whole sector
+const MIN_WRITE_INTERVAL_MS: u64 = 2 * 60 * 1000;
+
+pub struct RelayConfig {
+    pub pins: Vec<u8>,
+    /// Most relay modules switch on with a low input
+    pub active_low: bool,
+    /// Protects the contacts from an automation switching them back and forth
+    pub min_switch_interval_ms: u64,
+    /// Relays in the same group are never on together, e.g. a motor's up and down.
+    /// One entry per pin, `None` for relays that aren't interlocked.
+    pub interlock_groups: Vec<Option<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BootState {
+    /// Safest for mains loads, nothing starts up unattended after a power cut
+    #[default]
+    Off,
+    On,
+    LastState,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct RelayState {
+    /// One entry per relay, in `RELAY_PINS` order
+    pub channels: Vec<bool>,
+    #[serde(default)]
+    pub removed: bool,
+    // Only sent when the owner changes it
+    #[serde(default, skip_serializing)]
+    pub boot_state: Option<BootState>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedState {
+    #[serde(default)]
+    boot_state: BootState,
+    #[serde(default)]
+    channels: Vec<bool>,
+}
+
+struct Channel {
+    output: Output<'static>,
+    is_on: bool,
+    // What it is going to, it can be held back by the switching interval or an interlock
+    target: bool,
+    switched_at: Option<u64>,
+    interlock_group: Option<u8>,
+}
+
+pub struct Relay {
+    channels: Vec<Channel>,
+    active_low: bool,
+    min_switch_interval_ms: u64,
+    saved: SavedState,
+    written_at: Option<u64>,
+    // An interlock changed what the server asked for
+    needs_report: bool,
+}
+
+impl Relay {
+    /// Switches the relays to their boot state right away, joining the network can take a while
+    pub fn new(config: RelayConfig, fs: &mut FlashStorage) -> Self {
+        let channels = config
+            .pins
+            .iter()
+            .zip(config.interlock_groups.iter())
+            .map(|(pin, interlock_group)| {
+                // Picked at build time, nothing else in the firmware claims them
+                let pin = unsafe { AnyPin::steal(*pin) };
+                Channel {
+                    output: Output::new(pin, off_level(config.active_low), OutputConfig::default()),
+                    is_on: false,
+                    target: false,
+                    switched_at: None,
+                    interlock_group: *interlock_group,
+                }
+            })
+            .collect();
+        let saved: SavedState = read_alternating_record(fs, RELAY_STATE_ADDR).unwrap_or_default();
+        let mut relay = Self {
+            channels,
+            active_low: config.active_low,
+            min_switch_interval_ms: config.min_switch_interval_ms,
+            saved,
+            written_at: None,
+            needs_report: false,
+        };
+        let boot_channels: Vec<bool> = match relay.saved.boot_state {
+            BootState::Off => Vec::new(),
+            BootState::On => relay.channels.iter().map(|_| true).collect(),
+            BootState::LastState => relay.saved.channels.clone(),
+        };
+        relay.set_targets(&boot_channels);
+        relay.update(now());
+        // The server doesn't know the relays were switched on by themselves
+        relay.needs_report = relay.saved.boot_state != BootState::Off;
+        relay
+    }
+
+    /// Missing entries are switched off, extra ones ignored
+    fn set_targets(&mut self, targets: &[bool]) {
+        for index in 0..self.channels.len() {
+            self.set_target(index, targets.get(index).copied().unwrap_or(false));
+        }
+    }
+
+    fn set_target(&mut self, index: usize, is_on: bool) {
+        self.channels[index].target = is_on;
+        let Some(group) = self.channels[index].interlock_group.filter(|_| is_on) else {
+            return;
+        };
+        // The last one asked for wins
+        for (other, channel) in self.channels.iter_mut().enumerate() {
+            if other != index && channel.interlock_group == Some(group) && channel.target {
+                channel.target = false;
+                self.needs_report = true;
+            }
+        }
+    }
+
+    /// Switches whatever is allowed to by now, the rest is retried from `poll`
+    fn update(&mut self, now: u64) {
+        for index in 0..self.channels.len() {
+            let channel = &self.channels[index];
+            if channel.target == channel.is_on || !self.may_switch(channel, now) {
+                continue;
+            }
+            // Break before make, interlocked relays have to be off for a while first
+            if channel.target && self.is_interlocked(index, now) {
+                continue;
+            }
+            let channel = &mut self.channels[index];
+            channel.is_on = channel.target;
+            channel.switched_at = Some(now);
+            let level = if channel.is_on == self.active_low {
+                Level::Low
+            } else {
+                Level::High
+            };
+            channel.output.set_level(level);
+        }
+        status_led::set_light_on(self.channels.iter().any(|channel| channel.is_on));
+    }
+
+    fn may_switch(&self, channel: &Channel, now: u64) -> bool {
+        channel
+            .switched_at
+            .is_none_or(|switched_at| now - switched_at >= self.min_switch_interval_ms)
+    }
+
+    fn is_interlocked(&self, index: usize, now: u64) -> bool {
+        let Some(group) = self.channels[index].interlock_group else {
+            return false;
+        };
+        self.channels.iter().enumerate().any(|(other, channel)| {
+            other != index
+                && channel.interlock_group == Some(group)
+                && (channel.is_on
+                    || channel
+                        .switched_at
+                        .is_some_and(|switched_at| now - switched_at < INTERLOCK_DEAD_TIME_MS))
+        })
+    }
+
+    fn targets(&self) -> Vec<bool> {
+        self.channels.iter().map(|channel| channel.target).collect()
+    }
+
+    fn write(&mut self, now: u64) {
+        self.written_at = Some(now);
+        if let Err(err) =
+            write_alternating_record(&mut FlashStorage::new(), RELAY_STATE_ADDR, &self.saved)
+        {
+            println!("{}", err);
+        }
+    }
+}
+
+fn off_level(active_low: bool) -> Level {
+    if active_low {
+        Level::High
+    } else {
+        Level::Low
+    }
+}
+
+impl Device for Relay {
+    type State = RelayState;
+
+    fn resource_path(&self, device_id: &str) -> String {
+        format!("relays/{}", device_id)
+    }
+
+    fn name(&self) -> &'static str {
+        "Fancy relays"
+    }
+
+    fn device_type(&self) -> u8 {
+        0x02
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            on_off: true,
+            channels: self.channels.len() as u8,
+            ..Default::default()
+        }
+    }
+
+    fn apply(&mut self, state: RelayState) {
+        if let Some(boot_state) = state.boot_state {
+            if boot_state != self.saved.boot_state {
+                self.saved.boot_state = boot_state;
+                // Rarely changes, so it is written right away
+                self.write(now());
+            }
+        }
+        self.set_targets(&state.channels);
+        self.update(now());
+    }
+
+    /// Where the relays are going, they can be held back for a bit
+    fn state(&self) -> RelayState {
+        RelayState {
+            channels: self.targets(),
+            removed: false,
+            boot_state: None,
+        }
+    }
+
+    fn is_removed(&self, state: &RelayState) -> bool {
+        state.removed
+    }
+
+    /// Short press toggles the first relay, double press the second one
+    fn on_button(&mut self, event: ButtonEvent) -> bool {
+        let index = match event {
+            ButtonEvent::ShortPress => 0,
+            ButtonEvent::DoublePress => 1,
+            _ => return false,
+        };
+        let Some(channel) = self.channels.get(index) else {
+            return false;
+        };
+        self.set_target(index, !channel.target);
+        self.update(now());
+        println!("Relay {} switched with the button", index);
+        true
+    }
+
+    fn poll(&mut self, _coap_client: &mut CoapClient, _device_id: &str) -> bool {
+        let now = now();
+        self.update(now);
+        let targets = self.targets();
+        if self.saved.boot_state == BootState::LastState
+            && targets != self.saved.channels
+            && self
+                .written_at
+                .is_none_or(|written_at| now - written_at >= MIN_WRITE_INTERVAL_MS)
+        {
+            self.saved.channels = targets;
+            self.write(now);
+        }
+        core::mem::take(&mut self.needs_report)
+    }
+}
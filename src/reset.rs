@@ -0,0 +1,100 @@
+use core::ops::Range;
+
+use embedded_storage::{nor_flash::NorFlash, ReadStorage};
+use esp_hal::system::software_reset;
+use esp_println::println;
+use esp_storage::FlashStorage;
+
+use crate::errors::ResetVerificationError;
+use crate::status_led::{self, Status};
+use crate::{
+    CALIBRATION_ADDR, CONFIG_ADDR, FLASH_SECTOR_SIZE, LIGHT_STATE_ADDR, LIGHT_STATE_SIZE,
+    NVS_END_ADDR, SECURITY_VERSION_ADDR, USER_STATE_ADDR, USER_STATE_SIZE,
+};
+
+// Only the read back goes through a buffer, erasing works on whole sectors
+const VERIFY_CHUNK_SIZE: usize = 1024;
+const MAX_ERASE_TRIES: u8 = 3;
+
+/// Each level erases everything the previous one does
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResetLevel {
+    /// Wi-Fi credentials and server address, the device goes back to pairing
+    Network,
+    /// Also the persisted light state and schedules
+    User,
+    /// Everything but what the factory provisioned, the ID, secret and brightness calibration.
+    /// The security version stays too, a reset mustn't open the way to old firmware.
+    Factory,
+}
+
+impl ResetLevel {
+    /// Whole sectors, the identity sector is in none of them
+    fn erased_ranges(self) -> &'static [Range<u32>] {
+        const NETWORK: [Range<u32>; 1] = [CONFIG_ADDR..CONFIG_ADDR + FLASH_SECTOR_SIZE];
+        const USER: [Range<u32>; 3] = [
+            CONFIG_ADDR..CONFIG_ADDR + FLASH_SECTOR_SIZE,
+            LIGHT_STATE_ADDR..LIGHT_STATE_ADDR + LIGHT_STATE_SIZE,
+            USER_STATE_ADDR..USER_STATE_ADDR + USER_STATE_SIZE,
+        ];
+        // The calibration and security version sectors sit between the network settings
+        // and the light state
+        const FACTORY: [Range<u32>; 3] = [
+            CONFIG_ADDR..CALIBRATION_ADDR,
+            SECURITY_VERSION_ADDR + FLASH_SECTOR_SIZE..NVS_END_ADDR,
+            USER_STATE_ADDR..USER_STATE_ADDR + USER_STATE_SIZE,
+        ];
+        match self {
+            ResetLevel::Network => &NETWORK,
+            ResetLevel::User => &USER,
+            ResetLevel::Factory => &FACTORY,
+        }
+    }
+}
+
+/// No point in returing anything since this resets the whole chip
+pub fn handle_device_reset(fs: &mut FlashStorage, level: ResetLevel) {
+    println!("{:?} reset", level);
+    let mut tries = 0;
+    while let Err(err) = erase(fs, level).and_then(|_| verify_reset(fs, level)) {
+        println!("{}", err);
+        status_led::set(Status::Error);
+        tries += 1;
+        if tries == MAX_ERASE_TRIES {
+            // Restarting anyway, staying up with half erased settings helps no one
+            break;
+        }
+    }
+    software_reset(); //maybe use software_reset_cpu
+}
+
+/// Erases every sector once, rewriting them with 0xff would erase each of them per write
+fn erase(fs: &mut FlashStorage, level: ResetLevel) -> Result<(), ResetVerificationError> {
+    for range in level.erased_ranges() {
+        NorFlash::erase(fs, range.start, range.end).map_err(|_| ResetVerificationError {
+            address: range.start,
+        })?;
+    }
+    Ok(())
+}
+
+/// Reads everything the reset level covers back and checks it is erased
+pub fn verify_reset(
+    fs: &mut FlashStorage,
+    level: ResetLevel,
+) -> Result<(), ResetVerificationError> {
+    let mut buf = [0u8; VERIFY_CHUNK_SIZE];
+    for range in level.erased_ranges() {
+        for address in range.clone().step_by(VERIFY_CHUNK_SIZE) {
+            let len = (range.end - address).min(VERIFY_CHUNK_SIZE as u32) as usize;
+            fs.read(address, &mut buf[..len])
+                .map_err(|_| ResetVerificationError { address })?;
+            if let Some(offset) = buf[..len].iter().position(|byte| *byte != 0xff) {
+                return Err(ResetVerificationError {
+                    address: address + offset as u32,
+                });
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use alloc::boxed::Box;
+use esp_hal::gpio::AnyPin;
+use esp_hal::ledc::channel::{self, ChannelHW, ChannelIFace};
+use esp_hal::ledc::timer::{self, TimerIFace};
+use esp_hal::ledc::{LSGlobalClkSource, Ledc, LowSpeed};
+use esp_hal::peripherals::LEDC;
+use esp_hal::time::Rate;
+
+const PWM_FREQUENCY_KHZ: u32 = 24;
+// 10 bit duty, 8 bits leave visible steps at the low end
+pub const MAX_DUTY: u16 = 1023;
+
+/// Three LEDC PWM channels driving the red, green and blue parts of a fixture
+pub struct RgbOutput {
+    channels: [channel::Channel<'static, LowSpeed>; 3],
+}
+
+// Channels keep a `&dyn TimerIFace`, which isn't Send. The timer is leaked and the
+// output is only ever used from inside a critical section, see `light`
+unsafe impl Send for RgbOutput {}
+
+impl RgbOutput {
+    /// Pins are red, green and blue, as given in the `RGB_PINS` build env
+    pub fn new(ledc: LEDC<'static>, pins: [u8; 3]) -> Self {
+        // Channels keep references to the timer, both live as long as the firmware does
+        let ledc = Box::leak(Box::new(Ledc::new(ledc)));
+        ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+        let pwm_timer = Box::leak(Box::new(ledc.timer::<LowSpeed>(timer::Number::Timer0)));
+        pwm_timer
+            .configure(timer::config::Config {
+                duty: timer::config::Duty::Duty10Bit,
+                clock_source: timer::LSClockSource::APBClk,
+                frequency: Rate::from_khz(PWM_FREQUENCY_KHZ),
+            })
+            .unwrap();
+        let pwm_timer: &'static _ = pwm_timer;
+        let numbers = [
+            channel::Number::Channel0,
+            channel::Number::Channel1,
+            channel::Number::Channel2,
+        ];
+        let channels = core::array::from_fn(|i| {
+            // The pins are picked at build time, nothing else in the firmware claims them
+            let pin = unsafe { AnyPin::steal(pins[i]) };
+            let mut channel = ledc.channel(numbers[i], pin);
+            channel
+                .configure(channel::config::Config {
+                    timer: pwm_timer,
+                    duty_pct: 0,
+                    pin_config: channel::config::PinConfig::PushPull,
+                })
+                .unwrap();
+            channel
+        });
+        Self { channels }
+    }
+
+    /// `color` is packed as 0xRRGGBB, every part gets scaled by `duty`
+    pub fn write(&mut self, color: i32, duty: u16) {
+        let parts = [(color >> 16) as u8, (color >> 8) as u8, color as u8];
+        for (channel, part) in self.channels.iter().zip(parts) {
+            channel.set_duty_hw(part as u32 * duty.min(MAX_DUTY) as u32 / 255);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Scenes kept on the device, so the server only has to send a scene ID to change the look.
+//! They are managed on the server and fetched from `lights/{device_id}/scenes`.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use anyhow::anyhow;
+use coap_lite::{MessageClass, ResponseType};
+use esp_println::println;
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize};
+
+use crate::coap::CoapClient;
+use crate::effects::Effect;
+use crate::light::{self, LightState};
+use crate::utils::{read_alternating_record, write_alternating_record};
+use crate::SCENES_ADDR;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Scene {
+    pub id: u8,
+    pub name: String,
+    pub brightness: u8,
+    pub color: i32,
+    #[serde(default)]
+    pub effect: Option<Effect>,
+    #[serde(default)]
+    pub transition_ms: u32,
+}
+
+impl Scene {
+    pub fn to_state(&self) -> LightState {
+        LightState {
+            is_on: true,
+            brightness: self.brightness,
+            color: self.color,
+            removed: false,
+            transition_ms: self.transition_ms,
+            effect: self.effect.clone(),
+            scene: Some(self.id),
+            scenes_version: None,
+            schedules_version: None,
+            power_on: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SceneStore {
+    /// Bumped by the server on every change, notifications carry it so we know when to fetch again
+    pub version: u32,
+    pub scenes: Vec<Scene>,
+}
+
+impl SceneStore {
+    pub fn find(&self, id: u8) -> Option<&Scene> {
+        self.scenes.iter().find(|scene| scene.id == id)
+    }
+
+    /// Scene after `current`, wrapping around, used to cycle through them with the button
+    pub fn next_after(&self, current: Option<u8>) -> Option<&Scene> {
+        let position = current
+            .and_then(|id| self.scenes.iter().position(|scene| scene.id == id))
+            .map_or(0, |position| position + 1);
+        self.scenes.get(position).or_else(|| self.scenes.first())
+    }
+}
+
+/// Notification that only names a scene instead of carrying a full state
+#[derive(Deserialize)]
+pub struct SceneRecall {
+    pub scene: u8,
+    #[serde(default)]
+    pub scenes_version: Option<u32>,
+}
+
+pub fn load_scenes(fs: &mut FlashStorage) -> SceneStore {
+    read_alternating_record(fs, SCENES_ADDR).unwrap_or_default()
+}
+
+pub fn store_scenes(fs: &mut FlashStorage, scenes: &SceneStore) -> Result<(), anyhow::Error> {
+    write_alternating_record(fs, SCENES_ADDR, scenes)
+}
+
+pub fn fetch_scenes(
+    coap_client: &mut CoapClient,
+    device_id: &str,
+) -> Result<SceneStore, anyhow::Error> {
+    let resp = coap_client.fetch(&format!("lights/{}/scenes", device_id))?;
+    if resp.header.code != MessageClass::Response(ResponseType::Content) {
+        return Err(anyhow!("Fetching scenes failed: {:?}", resp.header.code));
+    }
+    serde_json::from_slice(&resp.payload).map_err(|_| anyhow!("Invalid scenes payload"))
+}
+
+/// Fetches the scenes again and applies the scene a notification asked for while they were outdated.
+/// Returns whether it was applied, the server only sent its ID and has to get the state reported
+pub fn sync_scenes(
+    coap_client: &mut CoapClient,
+    device_id: &str,
+    scenes: &mut SceneStore,
+    pending_scene: Option<u8>,
+) -> bool {
+    match fetch_scenes(coap_client, device_id) {
+        Ok(fetched) => {
+            // Checked at every boot, only touch the flash when something changed
+            if fetched.version != scenes.version {
+                if let Err(err) = store_scenes(&mut FlashStorage::new(), &fetched) {
+                    println!("{}", err);
+                }
+            }
+            *scenes = fetched;
+        }
+        // Keeping the stored ones, the next notification with a newer version tries again
+        Err(err) => println!("Could not fetch scenes: {}", err),
+    }
+    let Some(id) = pending_scene else {
+        return false;
+    };
+    match scenes.find(id) {
+        Some(scene) => {
+            light::apply(scene.to_state());
+            true
+        }
+        None => {
+            println!("Unknown scene {}", id);
+            false
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Schedules that run on the device, so the light keeps its routine without the server.
+//! They are managed on the server and fetched from `lights/{device_id}/schedules`.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use anyhow::anyhow;
+use coap_lite::{MessageClass, ResponseType};
+use esp_println::println;
+use esp_storage::FlashStorage;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{self, TimeZone};
+use crate::coap::CoapClient;
+use crate::light::{self, LightState};
+use crate::scenes::SceneStore;
+use crate::utils::{read_alternating_record, write_alternating_record};
+use crate::SCHEDULES_ADDR;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    /// Local time of day
+    Time {
+        minute_of_day: u16,
+    },
+    Sunrise {
+        offset_minutes: i16,
+    },
+    Sunset {
+        offset_minutes: i16,
+    },
+    /// One shot, e.g. a sleep timer, in unix seconds
+    Timer {
+        at: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    On {
+        #[serde(default)]
+        brightness: Option<u8>,
+        #[serde(default)]
+        transition_ms: u32,
+    },
+    Off {
+        #[serde(default)]
+        transition_ms: u32,
+    },
+    Scene {
+        scene: u8,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Schedule {
+    pub id: u8,
+    /// Days the rule runs on, bit 0 is Monday. Ignored by timers.
+    #[serde(default = "every_day")]
+    pub days: u8,
+    pub trigger: Trigger,
+    pub action: Action,
+}
+
+fn every_day() -> u8 {
+    0b0111_1111
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ScheduleStore {
+    /// Bumped by the server on every change, like the scene version
+    pub version: u32,
+    /// Needed for sunrise and sunset
+    #[serde(default)]
+    pub latitude: f64,
+    #[serde(default)]
+    pub longitude: f64,
+    /// Only used without a time zone, it doesn't follow DST
+    #[serde(default)]
+    pub utc_offset_minutes: i16,
+    /// POSIX TZ rule, takes precedence over the one from the build env
+    #[serde(default)]
+    pub time_zone: Option<String>,
+    pub schedules: Vec<Schedule>,
+    /// Server time when it sent the schedules, the device doesn't know it otherwise
+    #[serde(default, skip_serializing)]
+    pub now: Option<u64>,
+}
+
+pub fn load_schedules(fs: &mut FlashStorage) -> ScheduleStore {
+    read_alternating_record(fs, SCHEDULES_ADDR).unwrap_or_default()
+}
+
+pub fn store_schedules(
+    fs: &mut FlashStorage,
+    schedules: &ScheduleStore,
+) -> Result<(), anyhow::Error> {
+    write_alternating_record(fs, SCHEDULES_ADDR, schedules)
+}
+
+pub fn fetch_schedules(
+    coap_client: &mut CoapClient,
+    device_id: &str,
+) -> Result<ScheduleStore, anyhow::Error> {
+    let resp = coap_client.fetch(&format!("lights/{}/schedules", device_id))?;
+    if resp.header.code != MessageClass::Response(ResponseType::Content) {
+        return Err(anyhow!("Fetching schedules failed: {:?}", resp.header.code));
+    }
+    serde_json::from_slice(&resp.payload).map_err(|_| anyhow!("Invalid schedules payload"))
+}
+
+pub struct Scheduler {
+    store: ScheduleStore,
+    time_zone: Option<TimeZone>,
+    // Unix seconds up to which schedules already ran
+    checked_until: Option<u64>,
+}
+
+impl Scheduler {
+    pub fn new(store: ScheduleStore) -> Self {
+        Self {
+            time_zone: parse_time_zone(&store),
+            store,
+            checked_until: None,
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.store.version
+    }
+
+    pub fn sync(&mut self, coap_client: &mut CoapClient, device_id: &str) {
+        match fetch_schedules(coap_client, device_id) {
+            Ok(fetched) => {
+                if let Some(server_now) = fetched.now {
+                    clock::set_unix_time(server_now);
+                }
+                // Checked at every boot, only touch the flash when something changed
+                if fetched.version != self.store.version {
+                    if let Err(err) = store_schedules(&mut FlashStorage::new(), &fetched) {
+                        println!("{}", err);
+                    }
+                }
+                self.time_zone = parse_time_zone(&fetched);
+                self.store = fetched;
+            }
+            Err(err) => println!("Could not fetch schedules: {}", err),
+        }
+    }
+
+    /// State from the last schedule that came due since the previous call.
+    /// Nothing runs until the clock is set, and nothing that was due before that.
+    pub fn poll(&mut self, scenes: &SceneStore) -> Option<LightState> {
+        let now = clock::unix_time()?;
+        let Some(checked_until) = self.checked_until.replace(now) else {
+            return None;
+        };
+        if now <= checked_until {
+            return None;
+        }
+        let mut due = None;
+        for schedule in self.store.schedules.iter() {
+            // The window can cross midnight, so both days are checked
+            let is_due = [checked_until, now].iter().any(|unix_secs| {
+                self.fire_time(schedule, *unix_secs)
+                    .is_some_and(|fire_time| checked_until < fire_time && fire_time <= now)
+            });
+            if is_due {
+                println!("Running schedule {}", schedule.id);
+                due = Some(schedule.action);
+            }
+        }
+        due.and_then(|action| action_state(action, scenes))
+    }
+
+    /// When `schedule` runs on the local day containing `unix_secs`
+    fn fire_time(&self, schedule: &Schedule, unix_secs: u64) -> Option<u64> {
+        if let Trigger::Timer { at } = schedule.trigger {
+            return Some(at);
+        }
+        let utc_offset_secs = self.utc_offset_at(unix_secs as i64);
+        let local_day = (unix_secs as i64 + utc_offset_secs).div_euclid(SECS_PER_DAY as i64);
+        // 1970-01-01 was a Thursday
+        let weekday = (local_day + 3).rem_euclid(7);
+        if schedule.days & (1 << weekday) == 0 {
+            return None;
+        }
+        let day_start = local_day * SECS_PER_DAY as i64 - utc_offset_secs;
+        let fire_time = match schedule.trigger {
+            Trigger::Time { minute_of_day } => day_start + minute_of_day as i64 * 60,
+            Trigger::Sunrise { offset_minutes } => {
+                let (sunrise, _) = sun_times(local_day, self.store.latitude, self.store.longitude)?;
+                sunrise + offset_minutes as i64 * 60
+            }
+            Trigger::Sunset { offset_minutes } => {
+                let (_, sunset) = sun_times(local_day, self.store.latitude, self.store.longitude)?;
+                sunset + offset_minutes as i64 * 60
+            }
+            Trigger::Timer { at } => return Some(at),
+        };
+        u64::try_from(fire_time).ok()
+    }
+
+    fn utc_offset_at(&self, unix_secs: i64) -> i64 {
+        match self.time_zone {
+            Some(time_zone) => time_zone.offset_at(unix_secs),
+            None => {
+                clock::utc_offset_at(unix_secs).unwrap_or(self.store.utc_offset_minutes as i64 * 60)
+            }
+        }
+    }
+}
+
+fn parse_time_zone(store: &ScheduleStore) -> Option<TimeZone> {
+    let rule = store.time_zone.as_deref()?;
+    let time_zone = TimeZone::parse(rule);
+    if time_zone.is_none() {
+        println!("Invalid time zone {}", rule);
+    }
+    time_zone
+}
+
+fn action_state(action: Action, scenes: &SceneStore) -> Option<LightState> {
+    let mut state = light::state();
+    match action {
+        Action::On {
+            brightness,
+            transition_ms,
+        } => {
+            state.is_on = true;
+            state.brightness = brightness.unwrap_or(state.brightness);
+            state.transition_ms = transition_ms;
+        }
+        Action::Off { transition_ms } => {
+            state.is_on = false;
+            state.transition_ms = transition_ms;
+        }
+        Action::Scene { scene } => {
+            let Some(scene) = scenes.find(scene) else {
+                println!("Schedule refers to unknown scene {}", scene);
+                return None;
+            };
+            return Some(scene.to_state());
+        }
+    }
+    Some(state)
+}
+
+/// Sunrise and sunset in unix seconds for a day since the epoch, `None` during polar day or night.
+/// The usual sunrise equation, accurate to a minute or two.
+fn sun_times(day: i64, latitude: f64, longitude: f64) -> Option<(i64, i64)> {
+    use libm::{acos, asin, cos, fmod, sin};
+    let to_radians = core::f64::consts::PI / 180.0;
+    // Days since the J2000 epoch, corrected for longitude
+    let mean_solar_time = (day - 10957) as f64 - longitude / 360.0;
+    let mean_anomaly = fmod(357.5291 + 0.98560028 * mean_solar_time, 360.0) * to_radians;
+    let center = 1.9148 * sin(mean_anomaly)
+        + 0.02 * sin(2.0 * mean_anomaly)
+        + 0.0003 * sin(3.0 * mean_anomaly);
+    let ecliptic_longitude =
+        fmod(mean_anomaly / to_radians + center + 180.0 + 102.9372, 360.0) * to_radians;
+    let transit = 2451545.0 + mean_solar_time + 0.0053 * sin(mean_anomaly)
+        - 0.0069 * sin(2.0 * ecliptic_longitude);
+    let declination = asin(sin(ecliptic_longitude) * sin(23.4397 * to_radians));
+    let latitude = latitude * to_radians;
+    let cos_hour_angle = (sin(-0.833 * to_radians) - sin(latitude) * sin(declination))
+        / (cos(latitude) * cos(declination));
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = acos(cos_hour_angle) / to_radians;
+    let to_unix = |julian_date: f64| ((julian_date - 2440587.5) * SECS_PER_DAY as f64) as i64;
+    Some((
+        to_unix(transit - hour_angle / 360.0),
+        to_unix(transit + hour_angle / 360.0),
+    ))
+}
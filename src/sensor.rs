@@ -0,0 +1,260 @@
+//! Analog sensor on GPIO34, and an SHT3x on I2C when one is connected.
+//! Readings are filtered, published when they change enough and kept while the server can't be reached.
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use esp_hal::analog::adc::{Adc, AdcConfig, AdcPin, Attenuation};
+use esp_hal::i2c::master::{Config, I2c};
+use esp_hal::peripherals::{ADC1, GPIO21, GPIO22, GPIO34, I2C0};
+use esp_hal::Blocking;
+use esp_println::println;
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::coap::CoapClient;
+use crate::device::{Capabilities, Device};
+use crate::sht3x::Sht3x;
+use crate::utils::now;
+
+// Averaged into one sample, a single conversion is noisy
+const OVERSAMPLING: u32 = 16;
+// Share of a new sample in the filtered value
+const FILTER_WEIGHT: f32 = 0.25;
+const TEMPERATURE_DELTA: f32 = 0.2;
+const HUMIDITY_DELTA: f32 = 1.0;
+// About two hours of readings changing every minute, the oldest are dropped first
+const MAX_BUFFERED: usize = 120;
+const MAX_BATCH: usize = 10;
+const RETRY_INTERVAL_MS: u64 = 30 * 1000;
+
+pub struct SensorConfig {
+    pub sample_interval_ms: u64,
+    /// Change of the filtered ADC value that gets published right away
+    pub publish_delta: u16,
+    /// Published at least this often, so the server can tell the sensor is alive
+    pub heartbeat_ms: u64,
+}
+
+/// Latest reading, also what notifications carry
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SensorState {
+    /// Filtered 12 bit reading
+    pub value: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub humidity: Option<f32>,
+}
+
+struct Reading {
+    state: SensorState,
+    sampled_at: u64,
+    // Unix time, `None` when the clock wasn't set yet
+    time: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct PublishedReading<'a> {
+    #[serde(flatten)]
+    state: &'a SensorState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<u64>,
+    /// How long ago it was sampled, for readings taken before the clock was set
+    age_ms: u64,
+}
+
+pub struct Sensor {
+    adc: Adc<'static, ADC1<'static>, Blocking>,
+    pin: AdcPin<GPIO34<'static>, ADC1<'static>>,
+    sht3x: Option<Sht3x>,
+    config: SensorConfig,
+    filtered: Option<f32>,
+    state: SensorState,
+    sampled_at: Option<u64>,
+    published: Option<SensorState>,
+    published_at: Option<u64>,
+    buffer: VecDeque<Reading>,
+    retry_at: Option<u64>,
+}
+
+impl Sensor {
+    pub fn new(
+        config: SensorConfig,
+        adc1: ADC1<'static>,
+        gpio34: GPIO34<'static>,
+        i2c0: I2C0<'static>,
+        sda: GPIO21<'static>,
+        scl: GPIO22<'static>,
+    ) -> Self {
+        let mut adc_config = AdcConfig::new();
+        // The whole 0-3.3 V range
+        let pin = adc_config.enable_pin(gpio34, Attenuation::_11dB);
+        let sht3x = I2c::new(i2c0, Config::default())
+            .ok()
+            .and_then(|i2c| Sht3x::probe(i2c.with_sda(sda).with_scl(scl)));
+        if sht3x.is_none() {
+            println!("No SHT3x found, only the ADC is sampled");
+        }
+        Self {
+            adc: Adc::new(adc1, adc_config),
+            pin,
+            sht3x,
+            config,
+            filtered: None,
+            state: SensorState::default(),
+            sampled_at: None,
+            published: None,
+            published_at: None,
+            buffer: VecDeque::new(),
+            retry_at: None,
+        }
+    }
+
+    fn read_adc(&mut self) -> u16 {
+        let mut sum = 0;
+        for _ in 0..OVERSAMPLING {
+            // Only fails while the conversion is still running
+            sum += loop {
+                if let Ok(value) = self.adc.read_oneshot(&mut self.pin) {
+                    break value as u32;
+                }
+            };
+        }
+        (sum / OVERSAMPLING) as u16
+    }
+
+    fn sample(&mut self) {
+        let value = self.read_adc() as f32;
+        let filtered = match self.filtered {
+            Some(filtered) => filtered + (value - filtered) * FILTER_WEIGHT,
+            None => value,
+        };
+        self.filtered = Some(filtered);
+        let climate = self.sht3x.as_mut().and_then(|sht3x| sht3x.measure());
+        self.state = SensorState {
+            value: filtered as u16,
+            temperature: climate.map(|(temperature, _)| temperature),
+            humidity: climate.map(|(_, humidity)| humidity),
+        };
+    }
+
+    fn should_publish(&self, now: u64) -> bool {
+        let Some(published) = &self.published else {
+            return true;
+        };
+        self.published_at
+            .is_none_or(|published_at| now - published_at >= self.config.heartbeat_ms)
+            || self.state.value.abs_diff(published.value) >= self.config.publish_delta
+            || changed(
+                self.state.temperature,
+                published.temperature,
+                TEMPERATURE_DELTA,
+            )
+            || changed(self.state.humidity, published.humidity, HUMIDITY_DELTA)
+    }
+
+    fn queue(&mut self, now: u64) {
+        if self.buffer.len() == MAX_BUFFERED {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(Reading {
+            state: self.state.clone(),
+            sampled_at: now,
+            time: clock::unix_time(),
+        });
+        self.published = Some(self.state.clone());
+        self.published_at = Some(now);
+    }
+
+    /// Sends the buffered readings oldest first, they stay buffered until the server takes them
+    fn flush(&mut self, coap_client: &mut CoapClient, device_id: &str, now: u64) {
+        if self.retry_at.is_some_and(|retry_at| now < retry_at) {
+            return;
+        }
+        let uri = format!("sensors/{}/readings", device_id);
+        while !self.buffer.is_empty() {
+            let batch: Vec<PublishedReading> = self
+                .buffer
+                .iter()
+                .take(MAX_BATCH)
+                .map(|reading| PublishedReading {
+                    state: &reading.state,
+                    time: reading.time,
+                    age_ms: now - reading.sampled_at,
+                })
+                .collect();
+            let payload = serde_json::to_vec(&batch).unwrap();
+            let sent = batch.len();
+            if let Err(err) = coap_client.make_post_request(&uri, payload) {
+                println!(
+                    "Could not publish readings, {} buffered: {}",
+                    self.buffer.len(),
+                    err
+                );
+                self.retry_at = Some(now + RETRY_INTERVAL_MS);
+                return;
+            }
+            self.buffer.drain(..sent);
+        }
+        self.retry_at = None;
+    }
+}
+
+/// Either side missing counts as a change, the sensor came or went
+fn changed(value: Option<f32>, published: Option<f32>, delta: f32) -> bool {
+    match (value, published) {
+        (Some(value), Some(published)) => libm::fabsf(value - published) >= delta,
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+impl Device for Sensor {
+    type State = SensorState;
+
+    fn resource_path(&self, device_id: &str) -> String {
+        format!("sensors/{}", device_id)
+    }
+
+    fn name(&self) -> &'static str {
+        "Fancy sensor"
+    }
+
+    fn device_type(&self) -> u8 {
+        0x03
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            read_only: true,
+            ..Default::default()
+        }
+    }
+
+    // Nothing to set, notifications only echo what was reported
+    fn apply(&mut self, _state: SensorState) {}
+
+    fn state(&self) -> SensorState {
+        self.state.clone()
+    }
+
+    /// Readings are published on their own resource, the state is never reported
+    fn poll(&mut self, coap_client: &mut CoapClient, device_id: &str) -> bool {
+        let now = now();
+        if self
+            .sampled_at
+            .is_none_or(|sampled_at| now - sampled_at >= self.config.sample_interval_ms)
+        {
+            self.sampled_at = Some(now);
+            self.sample();
+            if self.should_publish(now) {
+                self.queue(now);
+            }
+        }
+        if !self.buffer.is_empty() {
+            self.flush(coap_client, device_id, now);
+        }
+        false
+    }
+}
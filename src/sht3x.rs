@@ -0,0 +1,63 @@
+//! Sensirion SHT3x temperature and humidity sensor on I2C, used when one answers at boot
+use esp_hal::delay::Delay;
+use esp_hal::i2c::master::I2c;
+use esp_hal::Blocking;
+
+// ADDR pin low, the common breakout default
+const ADDRESS: u8 = 0x44;
+// Single shot, high repeatability, no clock stretching
+const MEASURE: [u8; 2] = [0x24, 0x00];
+const MEASURE_MS: u32 = 16;
+const SOFT_RESET: [u8; 2] = [0x30, 0xA2];
+
+pub struct Sht3x {
+    i2c: I2c<'static, Blocking>,
+}
+
+impl Sht3x {
+    /// `None` when nothing answers, the sensor is optional
+    pub fn probe(mut i2c: I2c<'static, Blocking>) -> Option<Self> {
+        i2c.write(ADDRESS, &SOFT_RESET).ok()?;
+        // Takes at most 1.5 ms to come back
+        Delay::new().delay_millis(2);
+        Some(Self { i2c })
+    }
+
+    /// Temperature in °C and relative humidity in %
+    pub fn measure(&mut self) -> Option<(f32, f32)> {
+        self.i2c.write(ADDRESS, &MEASURE).ok()?;
+        Delay::new().delay_millis(MEASURE_MS);
+        let mut data = [0u8; 6];
+        self.i2c.read(ADDRESS, &mut data).ok()?;
+        let temperature = word(&data[0..3])?;
+        let humidity = word(&data[3..6])?;
+        Some((
+            -45.0 + 175.0 * temperature as f32 / 65535.0,
+            100.0 * humidity as f32 / 65535.0,
+        ))
+    }
+}
+
+/// Big endian word followed by its CRC
+fn word(data: &[u8]) -> Option<u16> {
+    if crc(&data[0..2]) != data[2] {
+        return None;
+    }
+    Some(u16::from_be_bytes([data[0], data[1]]))
+}
+
+/// CRC-8 with polynomial 0x31 starting from 0xFF, from the datasheet
+fn crc(data: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
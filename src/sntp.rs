@@ -0,0 +1,113 @@
+//! SNTP client (RFC 4330) on its own UDP socket, keeps the wall clock in `clock` set
+use anyhow::anyhow;
+use blocking_network_stack::UdpSocket;
+use esp_println::println;
+use esp_wifi::wifi::WifiDevice;
+use smoltcp::wire::IpAddress;
+
+use crate::clock;
+use crate::utils::now;
+
+const NTP_PORT: u16 = 123;
+const PACKET_SIZE: usize = 48;
+// Seconds from 1900, where NTP starts counting, to 1970
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+const RESPONSE_TIMEOUT_MS: u64 = 2000;
+const SYNC_INTERVAL_MS: u64 = 60 * 60 * 1000;
+const RETRY_INTERVAL_MS: u64 = 60 * 1000;
+
+pub struct SntpClient<'a, 'b> {
+    socket: UdpSocket<'a, 'b, WifiDevice<'a>>,
+    server: IpAddress,
+    next_sync_at: u64,
+}
+
+impl<'a, 'b> SntpClient<'a, 'b> {
+    pub fn new(socket: UdpSocket<'a, 'b, WifiDevice<'a>>, server: IpAddress) -> Self {
+        Self {
+            socket,
+            server,
+            next_sync_at: 0,
+        }
+    }
+
+    /// Syncs the clock when it is due, meant to be called from the main loop
+    pub fn poll(&mut self) {
+        if now() < self.next_sync_at {
+            return;
+        }
+        match self.sync() {
+            Ok(()) => {
+                self.next_sync_at = now() + SYNC_INTERVAL_MS;
+                if let Some(local_time) = clock::local_time() {
+                    println!("Clock synced, it is {}", local_time);
+                }
+            }
+            Err(err) => {
+                println!("SNTP failed: {}", err);
+                self.next_sync_at = now() + RETRY_INTERVAL_MS;
+            }
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), anyhow::Error> {
+        let mut request = [0u8; PACKET_SIZE];
+        // No leap second warning, version 4, client mode
+        request[0] = 0b00_100_011;
+        let sent_at = now();
+        // The server echoes it back, which tells its reply apart from late ones to earlier requests
+        request[40..48].copy_from_slice(&sent_at.to_be_bytes());
+        self.socket
+            .send(self.server, NTP_PORT, &request)
+            .map_err(|_| anyhow!("error sending SNTP request"))?;
+        let wait_end = sent_at + RESPONSE_TIMEOUT_MS;
+        let mut response = [0u8; PACKET_SIZE];
+        loop {
+            self.socket.work();
+            // Receive doesn't block, an error usually just means nothing arrived yet
+            if let Ok((len, address, port)) = self.socket.receive(&mut response) {
+                if len >= PACKET_SIZE
+                    && address == self.server
+                    && port == NTP_PORT
+                    && response[24..32] == request[40..48]
+                {
+                    break;
+                }
+            }
+            if now() > wait_end {
+                return Err(anyhow!("SNTP server did not answer"));
+            }
+        }
+        let received_at = now();
+        let mode = response[0] & 0b111;
+        let stratum = response[1];
+        // Stratum 0 is a kiss-o'-death, the server wants us to back off
+        if mode != 4 || stratum == 0 {
+            return Err(anyhow!("SNTP server refused, stratum {}", stratum));
+        }
+        let (Some(server_received_ms), Some(server_sent_ms)) = (
+            ntp_to_unix_ms(&response[32..40]),
+            ntp_to_unix_ms(&response[40..48]),
+        ) else {
+            return Err(anyhow!("SNTP server sent a time before 1970"));
+        };
+        // Half of the round trip, without the time the server held on to the request
+        let server_held_ms = server_sent_ms.saturating_sub(server_received_ms);
+        let delay_ms = (received_at - sent_at).saturating_sub(server_held_ms);
+        clock::sync(server_sent_ms + delay_ms / 2, received_at);
+        Ok(())
+    }
+}
+
+/// NTP timestamp, seconds since 1900 and a binary fraction, in unix milliseconds.
+/// `None` for times before 1970, only a broken server sends those
+fn ntp_to_unix_ms(timestamp: &[u8]) -> Option<u64> {
+    let mut secs = u32::from_be_bytes(timestamp[0..4].try_into().unwrap()) as u64;
+    let fraction = u32::from_be_bytes(timestamp[4..8].try_into().unwrap()) as u64;
+    // The seconds wrap in 2036, values with the top bit clear are from after that
+    if secs & 0x8000_0000 == 0 {
+        secs += 1 << 32;
+    }
+    let unix_secs = secs.checked_sub(NTP_UNIX_OFFSET_SECS)?;
+    Some(unix_secs * 1000 + ((fraction * 1000) >> 32))
+}
@@ -0,0 +1,105 @@
+//! Blink patterns on the GPIO2 LED, so a light can be diagnosed without a serial console.
+//! Driven from the ticker, setting a pattern never blocks.
+use core::cell::RefCell;
+
+use critical_section::{CriticalSection, Mutex};
+use esp_hal::gpio::Output;
+
+use crate::utils::now;
+
+const SLOT_MS: u64 = 100;
+const PATTERN_SLOTS: u64 = 20;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Status {
+    /// Off, or following the light in debug builds
+    Idle,
+    Pairing,
+    ConnectingWifi,
+    WaitingForDhcp,
+    ServerUnreachable,
+    OtaInProgress,
+    Error,
+}
+
+impl Status {
+    /// One bit per 100 ms slot of a two second cycle, most significant bit first
+    fn pattern(self) -> u32 {
+        match self {
+            Status::Idle => 0,
+            // Slow blink
+            Status::Pairing => 0b11111111110000000000,
+            // Fast blink
+            Status::ConnectingWifi => 0b10101010101010101010,
+            Status::WaitingForDhcp => 0b11001100110011001100,
+            // Double blink
+            Status::ServerUnreachable => 0b10100000000000000000,
+            // Mostly on, with a short gap every second
+            Status::OtaInProgress => 0b11111111101111111110,
+            // Triple blink
+            Status::Error => 0b10101000000000000000,
+        }
+    }
+}
+
+struct StatusLed {
+    output: Output<'static>,
+    status: Status,
+    started_at: u64,
+    // Only mirrored in debug builds, like the LED used to
+    mirror_light: bool,
+    is_light_on: bool,
+}
+
+static STATUS_LED: Mutex<RefCell<Option<StatusLed>>> = Mutex::new(RefCell::new(None));
+
+pub fn init(output: Output<'static>, mirror_light: bool) {
+    critical_section::with(|cs| {
+        STATUS_LED.borrow_ref_mut(cs).replace(StatusLed {
+            output,
+            status: Status::Idle,
+            started_at: 0,
+            mirror_light,
+            is_light_on: false,
+        })
+    });
+}
+
+pub fn set(status: Status) {
+    critical_section::with(|cs| {
+        if let Some(led) = STATUS_LED.borrow_ref_mut(cs).as_mut() {
+            if led.status != status {
+                led.status = status;
+                // Restart the pattern so it's recognizable from the first blink
+                led.started_at = now();
+            }
+        }
+    });
+}
+
+#[cfg_attr(not(any(feature = "light", feature = "relay")), allow(dead_code))]
+pub fn set_light_on(is_on: bool) {
+    critical_section::with(|cs| {
+        if let Some(led) = STATUS_LED.borrow_ref_mut(cs).as_mut() {
+            led.is_light_on = is_on;
+        }
+    });
+}
+
+pub(crate) fn tick(cs: CriticalSection, now: u64) {
+    let mut led = STATUS_LED.borrow_ref_mut(cs);
+    let Some(led) = led.as_mut() else {
+        return;
+    };
+    let is_high = if led.status == Status::Idle {
+        led.mirror_light && led.is_light_on
+    } else {
+        let slot = (now.saturating_sub(led.started_at) / SLOT_MS) % PATTERN_SLOTS;
+        led.status.pattern() & (1 << (PATTERN_SLOTS - 1 - slot)) != 0
+    };
+    if is_high {
+        led.output.set_high();
+    } else {
+        led.output.set_low();
+    }
+}
@@ -0,0 +1,40 @@
+//! Periodic timer interrupt for work that has to keep running while the
+//! main loop is blocked on the network
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use esp_hal::handler;
+use esp_hal::time::Duration;
+use esp_hal::timer::PeriodicTimer;
+use esp_hal::Blocking;
+
+#[cfg(feature = "light")]
+use crate::light;
+use crate::utils::now;
+use crate::{button, status_led};
+
+pub const TICK_MS: u64 = 10;
+
+static TICKER: Mutex<RefCell<Option<PeriodicTimer<'static, Blocking>>>> =
+    Mutex::new(RefCell::new(None));
+
+pub fn start(mut timer: PeriodicTimer<'static, Blocking>) {
+    timer.set_interrupt_handler(on_tick);
+    timer.listen();
+    timer.start(Duration::from_millis(TICK_MS)).unwrap();
+    critical_section::with(|cs| TICKER.borrow_ref_mut(cs).replace(timer));
+}
+
+#[handler]
+fn on_tick() {
+    critical_section::with(|cs| {
+        if let Some(timer) = TICKER.borrow_ref_mut(cs).as_mut() {
+            timer.clear_interrupt();
+        }
+        let now = now();
+        button::tick(cs, now);
+        status_led::tick(cs, now);
+        #[cfg(feature = "light")]
+        light::tick(cs, now);
+    });
+}
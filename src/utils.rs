@@ -1,6 +1,13 @@
+#[cfg(feature = "relay")]
+use core::marker::PhantomData;
 use core::str;
 
-use crate::{CONFIG_ADDR, ID_ADDR, SECRET_ADDR};
+use crate::clock::TimeZone;
+#[cfg(feature = "relay")]
+use crate::relay::RelayConfig;
+#[cfg(feature = "sensor")]
+use crate::sensor::SensorConfig;
+use crate::{CONFIG_ADDR, FLASH_SECTOR_SIZE, ID_ADDR, MAINTENANCE_ADDR, SECRET_ADDR, SERVER_ADDR};
 use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
@@ -9,30 +16,66 @@ use bleps::HciConnector;
 use embedded_storage::{ReadStorage, Storage};
 use esp_backtrace as _;
 use esp_hal::clock::CpuClock;
-use esp_hal::peripherals::{DAC2, GPIO2, GPIO26, GPIO4};
+#[cfg(feature = "sensor")]
+use esp_hal::peripherals::{ADC1, GPIO21, GPIO22, GPIO34, I2C0};
+#[cfg(feature = "light")]
+use esp_hal::peripherals::{DAC2, GPIO26, LEDC};
+use esp_hal::peripherals::{GPIO2, GPIO4};
 use esp_hal::rng::Rng;
 use esp_hal::system::software_reset;
 use esp_hal::time;
 use esp_hal::timer::timg::TimerGroup;
+use esp_hal::timer::PeriodicTimer;
+use esp_hal::Blocking;
 use esp_println::println;
 use esp_storage::FlashStorage;
 use esp_wifi::ble::controller::BleConnector;
 use esp_wifi::init;
 use esp_wifi::wifi::{WifiController, WifiDevice};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
 use smoltcp::iface::Interface;
 use smoltcp::wire::{IpAddress, Ipv4Address};
 
-pub fn init_hardware<'a>() -> (
-    Rng,
-    HciConnector<BleConnector<'static>>,
-    WifiController<'static>,
-    Interface,
-    WifiDevice<'a>,
-    GPIO26<'a>,
-    GPIO2<'a>,
-    GPIO4<'a>,
-    DAC2<'a>,
-) {
+pub struct Hardware<'a> {
+    pub rng: Rng,
+    pub hci: HciConnector<BleConnector<'static>>,
+    pub controller: WifiController<'static>,
+    pub iface: Interface,
+    pub device: WifiDevice<'a>,
+    // Only used by the captive portal
+    pub ap_device: WifiDevice<'a>,
+    pub gpio2: GPIO2<'a>,
+    pub gpio4: GPIO4<'a>,
+    pub ticker_timer: PeriodicTimer<'a, Blocking>,
+    pub device_peripherals: DevicePeripherals<'a>,
+}
+
+/// Peripherals only the device profile the firmware is built for uses
+pub struct DevicePeripherals<'a> {
+    #[cfg(feature = "light")]
+    pub gpio26: GPIO26<'a>,
+    #[cfg(feature = "light")]
+    pub dac2: DAC2<'a>,
+    #[cfg(feature = "light")]
+    pub ledc: LEDC<'a>,
+    // The relay pin comes from the build env
+    #[cfg(feature = "relay")]
+    pub _marker: PhantomData<&'a ()>,
+    #[cfg(feature = "sensor")]
+    pub adc1: ADC1<'a>,
+    #[cfg(feature = "sensor")]
+    pub gpio34: GPIO34<'a>,
+    // The default I2C pins, for an optional SHT3x
+    #[cfg(feature = "sensor")]
+    pub i2c0: I2C0<'a>,
+    #[cfg(feature = "sensor")]
+    pub gpio21: GPIO21<'a>,
+    #[cfg(feature = "sensor")]
+    pub gpio22: GPIO22<'a>,
+}
+
+pub fn init_hardware<'a>() -> Hardware<'a> {
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
     let rng = Rng::new(peripherals.RNG);
@@ -48,16 +91,42 @@ pub fn init_hardware<'a>() -> (
 
     let mut device = interfaces.sta;
     let iface = create_interface(&mut device);
-    let gpio26 = peripherals.GPIO26;
-    let gpio2 = peripherals.GPIO2;
-    let gpio4 = peripherals.GPIO4;
-    let dac2 = peripherals.DAC2;
+    // TIMG0 belongs to esp-wifi
+    let timg1 = TimerGroup::new(peripherals.TIMG1);
     controller
         .set_power_saving(esp_wifi::config::PowerSaveMode::None)
         .unwrap();
-    (
-        rng, hci, controller, iface, device, gpio26, gpio2, gpio4, dac2,
-    )
+    Hardware {
+        rng,
+        hci,
+        controller,
+        iface,
+        device,
+        ap_device: interfaces.ap,
+        gpio2: peripherals.GPIO2,
+        gpio4: peripherals.GPIO4,
+        ticker_timer: PeriodicTimer::new(timg1.timer0),
+        device_peripherals: DevicePeripherals {
+            #[cfg(feature = "light")]
+            gpio26: peripherals.GPIO26,
+            #[cfg(feature = "light")]
+            dac2: peripherals.DAC2,
+            #[cfg(feature = "light")]
+            ledc: peripherals.LEDC,
+            #[cfg(feature = "relay")]
+            _marker: PhantomData,
+            #[cfg(feature = "sensor")]
+            adc1: peripherals.ADC1,
+            #[cfg(feature = "sensor")]
+            gpio34: peripherals.GPIO34,
+            #[cfg(feature = "sensor")]
+            i2c0: peripherals.I2C0,
+            #[cfg(feature = "sensor")]
+            gpio21: peripherals.GPIO21,
+            #[cfg(feature = "sensor")]
+            gpio22: peripherals.GPIO22,
+        },
+    }
 }
 pub fn actual_ip(ip: &str) -> [u8; 4] {
     let vec: Vec<u8> = ip
@@ -92,9 +161,202 @@ pub fn get_env() -> (u16, IpAddress, bool) {
     ));
     (port, ip_address, debug_env)
 }
+/// Pairing window length and BLE advertising interval, both optional in the build env
+pub fn get_pairing_env() -> (u64, u16) {
+    let pairing_window_minutes: u64 = match option_env!("PAIRING_WINDOW_MINUTES") {
+        Some(val) => val
+            .parse::<u64>()
+            .expect("Invalid PAIRING_WINDOW_MINUTES value"),
+        None => 5,
+    };
+    let advertising_interval_ms: u16 = match option_env!("BLE_ADV_INTERVAL_MS") {
+        Some(val) => val
+            .parse::<u16>()
+            .expect("Invalid BLE_ADV_INTERVAL_MS value"),
+        None => 200,
+    };
+    (pairing_window_minutes * 60 * 1000, advertising_interval_ms)
+}
+/// Red, green and blue pins of an RGB fixture, the DAC output is used without them
+#[cfg(feature = "light")]
+pub fn get_rgb_env() -> Option<[u8; 3]> {
+    let pins: Vec<u8> = option_env!("RGB_PINS")?
+        .split(',')
+        .map(|pin| pin.trim().parse::<u8>().expect("Invalid RGB_PINS value"))
+        .collect();
+    // GPIO26 stays with the DAC, which is handed out even when it isn't driven
+    check_output_pins("RGB_PINS", &pins, &[26]);
+    Some(
+        pins.as_slice()
+            .try_into()
+            .expect("RGB_PINS needs three pins"),
+    )
+}
+/// Pins from the build env are stolen, so nothing else checks that they can drive an output.
+/// Profiles are built on their own, RGB and relay pins never exist in the same firmware
+#[cfg(any(feature = "light", feature = "relay"))]
+fn check_output_pins(name: &str, pins: &[u8], reserved: &[u8]) {
+    for (i, pin) in pins.iter().enumerate() {
+        assert!(
+            *pin != 2 && *pin != 4,
+            "{}: GPIO2 and GPIO4 are used by the status LED and the button",
+            name
+        );
+        assert!(
+            !(6..=11).contains(pin),
+            "{}: GPIO6 to GPIO11 are connected to the flash",
+            name
+        );
+        assert!(
+            *pin < 34,
+            "{}: GPIO34 to GPIO39 are inputs only, GPIO{} can't drive an output",
+            name,
+            pin
+        );
+        assert!(
+            ![20, 24, 28, 29, 30, 31].contains(pin),
+            "{}: there is no GPIO{}",
+            name,
+            pin
+        );
+        assert!(
+            !reserved.contains(pin),
+            "{}: GPIO{} is already used",
+            name,
+            pin
+        );
+        assert!(
+            !pins[..i].contains(pin),
+            "{}: GPIO{} is listed twice",
+            name,
+            pin
+        );
+    }
+}
+/// Model and hardware revision from `MODEL` and `HW_REVISION`, named after the profile by default
+pub fn get_hardware_env() -> (&'static str, &'static str) {
+    #[cfg(feature = "light")]
+    let default_model = "diy-iot-light";
+    #[cfg(feature = "relay")]
+    let default_model = "diy-iot-relay";
+    #[cfg(feature = "sensor")]
+    let default_model = "diy-iot-sensor";
+    (
+        option_env!("MODEL").unwrap_or(default_model),
+        option_env!("HW_REVISION").unwrap_or("1"),
+    )
+}
+/// Key firmware images are signed with and this build's security version.
+/// Without `FIRMWARE_PUBLIC_KEY` no update is accepted.
+pub fn get_firmware_env() -> (Option<[u8; 32]>, u32) {
+    let public_key = option_env!("FIRMWARE_PUBLIC_KEY").map(|hex| {
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .expect("Invalid FIRMWARE_PUBLIC_KEY value")
+            })
+            .collect();
+        bytes
+            .as_slice()
+            .try_into()
+            .expect("FIRMWARE_PUBLIC_KEY needs 32 bytes")
+    });
+    let security_version = match option_env!("SECURITY_VERSION") {
+        Some(val) => val.parse::<u32>().expect("Invalid SECURITY_VERSION value"),
+        None => 0,
+    };
+    (public_key, security_version)
+}
+/// SNTP server from `NTP_SERVER` and POSIX time zone rule from `DEVICE_TZ`, both optional.
+/// Not `TZ`, build machines often set that to a zone name which isn't a POSIX rule
+pub fn get_time_env() -> (Option<IpAddress>, Option<TimeZone>) {
+    let ntp_server = option_env!("NTP_SERVER").map(|ip_env| {
+        let ip_address_bytes = actual_ip(ip_env);
+        IpAddress::Ipv4(Ipv4Address::new(
+            ip_address_bytes[0],
+            ip_address_bytes[1],
+            ip_address_bytes[2],
+            ip_address_bytes[3],
+        ))
+    });
+    let time_zone = option_env!("DEVICE_TZ").and_then(|rule| {
+        let time_zone = TimeZone::parse(rule);
+        if time_zone.is_none() {
+            println!("Invalid DEVICE_TZ value {}, using UTC", rule);
+        }
+        time_zone
+    });
+    (ntp_server, time_zone)
+}
+/// Relays from `RELAY_PINS`, GPIO26 by default since relay boards have no DAC output to drive.
+/// `RELAY_INTERLOCK` gives each pin a group number, or `-` to leave it out of any group.
+#[cfg(feature = "relay")]
+pub fn get_relay_env() -> RelayConfig {
+    let pins: Vec<u8> = option_env!("RELAY_PINS")
+        .unwrap_or("26")
+        .split(',')
+        .map(|pin| pin.trim().parse::<u8>().expect("Invalid RELAY_PINS value"))
+        .collect();
+    check_output_pins("RELAY_PINS", &pins, &[]);
+    let active_low = match option_env!("RELAY_ACTIVE_LOW") {
+        Some(val) => val.parse::<bool>().expect("Invalid RELAY_ACTIVE_LOW value"),
+        None => false,
+    };
+    let min_switch_interval_ms = match option_env!("RELAY_MIN_SWITCH_MS") {
+        Some(val) => val
+            .parse::<u64>()
+            .expect("Invalid RELAY_MIN_SWITCH_MS value"),
+        None => 1000,
+    };
+    let interlock_groups: Vec<Option<u8>> = match option_env!("RELAY_INTERLOCK") {
+        Some(val) => val
+            .split(',')
+            .map(|group| match group.trim() {
+                "-" => None,
+                group => Some(group.parse::<u8>().expect("Invalid RELAY_INTERLOCK value")),
+            })
+            .collect(),
+        None => pins.iter().map(|_| None).collect(),
+    };
+    assert!(
+        interlock_groups.len() == pins.len(),
+        "RELAY_INTERLOCK needs an entry for every relay pin"
+    );
+    RelayConfig {
+        pins,
+        active_low,
+        min_switch_interval_ms,
+        interlock_groups,
+    }
+}
+/// Sampling and publishing from `SENSOR_INTERVAL_S`, `SENSOR_DELTA` and `SENSOR_HEARTBEAT_S`
+#[cfg(feature = "sensor")]
+pub fn get_sensor_env() -> SensorConfig {
+    let sample_interval_s = match option_env!("SENSOR_INTERVAL_S") {
+        Some(val) => val.parse::<u64>().expect("Invalid SENSOR_INTERVAL_S value"),
+        None => 60,
+    };
+    let publish_delta = match option_env!("SENSOR_DELTA") {
+        Some(val) => val.parse::<u16>().expect("Invalid SENSOR_DELTA value"),
+        None => 20,
+    };
+    let heartbeat_s = match option_env!("SENSOR_HEARTBEAT_S") {
+        Some(val) => val
+            .parse::<u64>()
+            .expect("Invalid SENSOR_HEARTBEAT_S value"),
+        None => 15 * 60,
+    };
+    assert!(sample_interval_s > 0, "SENSOR_INTERVAL_S can't be 0");
+    SensorConfig {
+        sample_interval_ms: sample_interval_s * 1000,
+        publish_delta,
+        heartbeat_ms: heartbeat_s * 1000,
+    }
+}
 pub fn get_device_data(fs: &mut FlashStorage) -> (String, String) {
-    let device_id_bytes = get_device_id(fs);
-    let device_id = str::from_utf8(&device_id_bytes).unwrap();
+    let device_id = get_device_id_string(fs);
     println!("{}", device_id);
 
     // Device secret is 344 bytes long
@@ -102,7 +364,39 @@ pub fn get_device_data(fs: &mut FlashStorage) -> (String, String) {
     // Converting with utf-8 resulted in errors in printable characters
     let device_secret = device_secret_bytes.as_ascii().unwrap().as_str();
     println!("{}", device_secret);
-    (String::from(device_id), String::from(device_secret))
+    (device_id, String::from(device_secret))
+}
+/// Server address entered during provisioning, erased flash means the one from the build env
+pub fn get_server_config(fs: &mut FlashStorage) -> Option<(IpAddress, u16)> {
+    let mut server_bytes = [0xffu8; 6];
+    fs.read(SERVER_ADDR, &mut server_bytes).unwrap();
+    if server_bytes == [0xff; 6] {
+        return None;
+    }
+    let ip_address = IpAddress::Ipv4(Ipv4Address::new(
+        server_bytes[0],
+        server_bytes[1],
+        server_bytes[2],
+        server_bytes[3],
+    ));
+    let port = u16::from_le_bytes([server_bytes[4], server_bytes[5]]);
+    Some((ip_address, port))
+}
+pub fn store_server_config(
+    fs: &mut FlashStorage,
+    ip_address: IpAddress,
+    port: u16,
+) -> Result<(), anyhow::Error> {
+    let IpAddress::Ipv4(ip_address) = ip_address;
+    let mut server_bytes = [0u8; 6];
+    server_bytes[0..4].copy_from_slice(&ip_address.octets());
+    server_bytes[4..6].copy_from_slice(&port.to_le_bytes());
+    fs.write(SERVER_ADDR, &server_bytes)
+        .map_err(|_| anyhow!("Could not write server address to flash"))
+}
+pub fn clear_server_config(fs: &mut FlashStorage) -> Result<(), anyhow::Error> {
+    fs.write(SERVER_ADDR, &[0xff; 6])
+        .map_err(|_| anyhow!("Could not clear server address in flash"))
 }
 pub fn is_device_configured(fs: &mut FlashStorage) -> bool {
     let mut config_bytes = [255u8; 4];
@@ -110,6 +404,11 @@ pub fn is_device_configured(fs: &mut FlashStorage) -> bool {
     config_bytes == [0, 0, 0, 0]
 }
 
+pub fn set_device_configured(fs: &mut FlashStorage) {
+    let config_bytes = [0u8; 4];
+    fs.write(CONFIG_ADDR, &config_bytes).unwrap();
+}
+
 pub fn now() -> u64 {
     time::Instant::now().duration_since_epoch().as_millis()
 }
@@ -136,18 +435,125 @@ pub fn get_device_id(fs: &mut FlashStorage) -> [u8; 36] {
     fs.read(ID_ADDR, &mut buf).unwrap();
     buf
 }
+pub fn get_device_id_string(fs: &mut FlashStorage) -> String {
+    let device_id_bytes = get_device_id(fs);
+    String::from(str::from_utf8(&device_id_bytes).unwrap())
+}
 pub fn get_device_secret(fs: &mut FlashStorage) -> [u8; 344] {
     let mut secret_buf: [u8; 344] = [0u8; 344];
     fs.read(SECRET_ADDR, &mut secret_buf).unwrap();
     secret_buf
 }
 
-/// No point in returing anything since this resets the whole chip
-// TODO consider wiping wifi credentials
-pub fn handle_device_reset(fs: &mut FlashStorage) {
-    let config_bytes = [0xff, 0xff, 0xff, 0xff];
-    fs.write(CONFIG_ADDR, &config_bytes).unwrap();
-    software_reset(); //maybe use software_reset_cpu
+/// Json stored with its length in front, `None` when there is nothing valid in flash
+#[cfg_attr(not(any(feature = "light", feature = "relay")), allow(dead_code))]
+pub fn read_json_record<T: DeserializeOwned>(
+    fs: &mut FlashStorage,
+    addr: u32,
+    size: u32,
+) -> Option<T> {
+    let mut len_bytes = [0u8; 2];
+    fs.read(addr, &mut len_bytes).unwrap();
+    let len = u16::from_le_bytes(len_bytes) as usize;
+    // Erased flash reads as 0xffff
+    if len == 0 || len > size as usize - 2 {
+        return None;
+    }
+    let mut json = alloc::vec![0u8; len];
+    fs.read(addr + 2, &mut json).unwrap();
+    let record = serde_json::from_slice(&json).ok();
+    if record.is_none() {
+        println!("Corrupted record at {:#x}", addr);
+    }
+    record
+}
+#[cfg_attr(not(any(feature = "light", feature = "relay")), allow(dead_code))]
+pub fn write_json_record<T: Serialize>(
+    fs: &mut FlashStorage,
+    addr: u32,
+    size: u32,
+    record: &T,
+) -> Result<(), anyhow::Error> {
+    let json = serde_json::to_vec(record).map_err(|_| anyhow!("Could not serialize record"))?;
+    if json.len() > size as usize - 2 {
+        return Err(anyhow!("Record too big for {} bytes of flash", size));
+    }
+    let mut bytes = Vec::with_capacity(json.len() + 2);
+    bytes.extend_from_slice(&(json.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(&json);
+    fs.write(addr, &bytes)
+        .map_err(|_| anyhow!("Could not write record to flash"))
+}
+#[cfg_attr(not(any(feature = "light", feature = "relay")), allow(dead_code))]
+#[derive(Serialize)]
+struct SlotRecord<'a, T> {
+    sequence: u32,
+    record: &'a T,
+}
+#[cfg_attr(not(any(feature = "light", feature = "relay")), allow(dead_code))]
+#[derive(Deserialize)]
+struct SavedSlot<T> {
+    sequence: u32,
+    record: T,
+}
+/// Address and content of the newer of the two slots starting at `addr`
+#[cfg_attr(not(any(feature = "light", feature = "relay")), allow(dead_code))]
+fn newest_slot<T: DeserializeOwned>(
+    fs: &mut FlashStorage,
+    addr: u32,
+) -> Option<(u32, SavedSlot<T>)> {
+    (0..2)
+        .filter_map(|slot| {
+            let slot_addr = addr + slot * FLASH_SECTOR_SIZE;
+            read_json_record::<SavedSlot<T>>(fs, slot_addr, FLASH_SECTOR_SIZE)
+                .map(|saved| (slot_addr, saved))
+        })
+        .max_by_key(|(_, saved)| saved.sequence)
+}
+/// Json record in two sectors of its own, see `write_alternating_record`
+#[cfg_attr(not(any(feature = "light", feature = "relay")), allow(dead_code))]
+pub fn read_alternating_record<T: DeserializeOwned>(fs: &mut FlashStorage, addr: u32) -> Option<T> {
+    newest_slot(fs, addr).map(|(_, saved)| saved.record)
+}
+/// Writes over the older of two sectors, so a power cut while writing only loses the new record
+#[cfg_attr(not(any(feature = "light", feature = "relay")), allow(dead_code))]
+pub fn write_alternating_record<T: Serialize>(
+    fs: &mut FlashStorage,
+    addr: u32,
+    record: &T,
+) -> Result<(), anyhow::Error> {
+    let (slot_addr, sequence) = match newest_slot::<IgnoredAny>(fs, addr) {
+        Some((newest_addr, saved)) if newest_addr == addr => {
+            (addr + FLASH_SECTOR_SIZE, saved.sequence + 1)
+        }
+        Some((_, saved)) => (addr, saved.sequence + 1),
+        None => (addr, 0),
+    };
+    write_json_record(
+        fs,
+        slot_addr,
+        FLASH_SECTOR_SIZE,
+        &SlotRecord { sequence, record },
+    )
+}
+/// Makes the next boot open the maintenance window before connecting
+/// Only restarts once the request is in flash
+pub fn request_maintenance(fs: &mut FlashStorage) -> Result<(), anyhow::Error> {
+    fs.write(MAINTENANCE_ADDR, &[0, 0, 0, 0])
+        .map_err(|_| anyhow!("Could not write maintenance request to flash"))?;
+    software_reset();
+}
+/// A request that can't be cleared is an error, opening the window on every boot is worse
+pub fn take_maintenance_request(fs: &mut FlashStorage) -> Result<bool, anyhow::Error> {
+    let mut request_bytes = [0xffu8; 4];
+    fs.read(MAINTENANCE_ADDR, &mut request_bytes)
+        .map_err(|_| anyhow!("Could not read maintenance request from flash"))?;
+    if request_bytes != [0, 0, 0, 0] {
+        return Ok(false);
+    }
+    fs.write(MAINTENANCE_ADDR, &[0xff; 4])
+        .map_err(|_| anyhow!("Could not clear maintenance request in flash"))?;
+    Ok(true)
 }
 #[allow(dead_code)]
 pub fn set_random_mac(mut rng: Rng) -> Result<(), anyhow::Error> {
@@ -1,13 +1,22 @@
-use crate::errors::{PasswordFlashError, SSIDFlashError};
-use crate::utils::is_device_configured;
-use crate::{pairing, PASS_ADDR, SSID_ADDR};
+use crate::button::ButtonEvent;
+use crate::captive_portal::{configure_ap_stack, CaptivePortal, PortalBuffers};
+use crate::coap::CoapClient;
+use crate::errors::{PasswordFlashError, SSIDFlashError, WifiConnectError};
+use crate::pairing::{PairingIdentity, PairingMode};
+use crate::status_led::{self, Status};
+use crate::utils::{
+    create_interface, get_pairing_env, is_device_configured, now, take_maintenance_request,
+};
+use crate::{button, pairing, PASS_ADDR, SSID_ADDR};
 use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
 use alloc::string::String;
 use bleps::HciConnector;
 use blocking_network_stack::{Stack, UdpSocket};
 use core::error::Error;
-use embedded_storage::ReadStorage;
+use embedded_storage::{ReadStorage, Storage};
+use esp_hal::delay::Delay;
+use esp_hal::rng::Rng;
 use esp_println::println;
 use esp_storage::FlashStorage;
 use esp_wifi::ble::controller::BleConnector;
@@ -17,6 +26,10 @@ use smoltcp::socket::udp::PacketMetadata;
 use smoltcp::wire::DhcpOption;
 
 const MAX_CONNECTION_TRIES: u8 = 5;
+const MAX_FAILED_CONNECTIONS_BEFORE_MAINTENANCE: u8 = 3;
+const MAX_SCANNED_NETWORKS: usize = 20;
+const DHCP_TIMEOUT_MS: u64 = 30 * 1000;
+const BUTTON_POLL_INTERVAL_MS: u32 = 50;
 #[derive(Copy, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 enum WifiFieldType {
@@ -39,6 +52,30 @@ fn read_wifi_field_from_flash(
     }
     Ok(ssid_result.unwrap().trim_matches(char::from(0)).to_owned())
 }
+/// Stores credentials in the same zero padded format the pairing service writes
+pub fn store_wifi_credentials(fs: &mut FlashStorage, ssid: &[u8], password: &[u8]) -> bool {
+    if ssid.len() > 128 || password.len() > 128 {
+        return false;
+    }
+    for (field_type, value) in [
+        (WifiFieldType::SSID, ssid),
+        (WifiFieldType::Password, password),
+    ] {
+        let mut buf = [0u8; 128];
+        buf[..value.len()].copy_from_slice(value);
+        fs.write(field_type as u32, &buf).unwrap();
+    }
+    true
+}
+/// Credentials as stored, used to put them back when changing them didn't work out
+pub fn read_raw_wifi_credentials(fs: &mut FlashStorage) -> ([u8; 128], [u8; 128]) {
+    let mut ssid_buf: [u8; 128] = [0u8; 128];
+    let mut password_buf: [u8; 128] = [0u8; 128];
+    fs.read(WifiFieldType::SSID as u32, &mut ssid_buf).unwrap();
+    fs.read(WifiFieldType::Password as u32, &mut password_buf)
+        .unwrap();
+    (ssid_buf, password_buf)
+}
 pub fn get_wifi_config() -> Result<Configuration, Box<dyn Error>> {
     let mut fs = FlashStorage::new();
     let ssid = read_wifi_field_from_flash(&mut fs, WifiFieldType::SSID)?;
@@ -54,17 +91,21 @@ pub fn get_wifi_config() -> Result<Configuration, Box<dyn Error>> {
         ..Default::default()
     }))
 }
-pub fn connect_to_wifi(controller: &mut WifiController, wifi_stack: &Stack<WifiDevice>) -> bool {
+pub fn connect_to_wifi(
+    controller: &mut WifiController,
+    wifi_stack: &Stack<WifiDevice>,
+) -> Result<(), WifiConnectError> {
     match get_wifi_config() {
         Ok(config) => try_connect_to_network(&config, controller, wifi_stack),
-        Err(_) => false,
+        Err(_) => Err(WifiConnectError::InvalidCredentials),
     }
 }
 pub fn try_connect_to_network(
     client_config: &Configuration,
     controller: &mut WifiController,
     wifi_stack: &Stack<WifiDevice>,
-) -> bool {
+) -> Result<(), WifiConnectError> {
+    status_led::set(Status::ConnectingWifi);
     let res = controller.set_configuration(client_config);
     println!("wifi_set_configuration returned {:?}", res);
     let mut connection_tries = 0;
@@ -86,7 +127,8 @@ pub fn try_connect_to_network(
                 println!("{:?}", err);
                 connection_tries += 1;
                 if connection_tries > MAX_CONNECTION_TRIES {
-                    return false;
+                    status_led::set(Status::Error);
+                    return Err(diagnose_connection_failure(client_config, controller));
                 }
             }
         }
@@ -95,14 +137,47 @@ pub fn try_connect_to_network(
 
     // wait for getting an ip address
     println!("Wait to get an ip address");
+    status_led::set(Status::WaitingForDhcp);
+    let dhcp_deadline = now() + DHCP_TIMEOUT_MS;
     loop {
         wifi_stack.work();
         if wifi_stack.is_iface_up() {
             println!("got ip {:?}", wifi_stack.get_ip_info());
             break;
         }
+        if now() > dhcp_deadline {
+            status_led::set(Status::Error);
+            return Err(WifiConnectError::DhcpTimeout);
+        }
+    }
+    Ok(())
+}
+
+/// The driver doesn't tell us why joining failed, but if the network
+/// is in range the password is the most likely culprit
+fn diagnose_connection_failure(
+    client_config: &Configuration,
+    controller: &mut WifiController,
+) -> WifiConnectError {
+    let Configuration::Client(client_config) = client_config else {
+        return WifiConnectError::InvalidCredentials;
+    };
+    match controller.scan_n(MAX_SCANNED_NETWORKS) {
+        Ok(networks) => {
+            if networks
+                .iter()
+                .any(|network| network.ssid == client_config.ssid)
+            {
+                WifiConnectError::WrongPassword
+            } else {
+                WifiConnectError::NoAccessPoint
+            }
+        }
+        Err(err) => {
+            println!("Scan failed: {:?}", err);
+            WifiConnectError::NoAccessPoint
+        }
     }
-    true
 }
 
 pub struct UdpSocketParamsWrapper {
@@ -131,17 +206,117 @@ pub fn setup_udp_socket<'a>(
     )
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn initialize_network_or_pair(
     hci: &HciConnector<BleConnector>,
     controller: &mut WifiController,
     fs: &mut FlashStorage,
     stack: &Stack<WifiDevice>,
+    mut ap_device: WifiDevice,
+    coap_client: &mut CoapClient,
+    identity: &PairingIdentity,
+    mut rng: Rng,
 ) {
+    let (pairing_window_ms, advertising_interval_ms) = get_pairing_env();
     if is_device_configured(fs) {
-        while !connect_to_wifi(controller, stack) {}
+        // Long press on the button asked for new settings before the reset
+        let is_maintenance_requested = take_maintenance_request(fs).unwrap_or_else(|err| {
+            println!("{}", err);
+            false
+        });
+        if is_maintenance_requested {
+            println!("Opening requested maintenance window");
+            controller.stop().unwrap();
+            if pairing::init_advertising(
+                hci,
+                controller,
+                stack,
+                coap_client,
+                identity,
+                rng,
+                PairingMode::Maintenance,
+                pairing_window_ms,
+                advertising_interval_ms,
+            ) {
+                return;
+            }
+        }
+        let mut failed_connections = 0;
+        while let Err(err) = connect_to_wifi(controller, stack) {
+            println!("Could not connect: {}", err);
+            failed_connections += 1;
+            // The network probably changed, let the owner update the settings
+            if failed_connections == MAX_FAILED_CONNECTIONS_BEFORE_MAINTENANCE {
+                failed_connections = 0;
+                println!("Opening maintenance window");
+                controller.stop().unwrap();
+                if pairing::init_advertising(
+                    hci,
+                    controller,
+                    stack,
+                    coap_client,
+                    identity,
+                    rng,
+                    PairingMode::Maintenance,
+                    pairing_window_ms,
+                    advertising_interval_ms,
+                ) {
+                    break;
+                }
+            }
+        }
     } else {
         controller.stop().unwrap();
-        while !pairing::init_advertising(hci, controller, stack) {}
+        // Buffers have to outlive the access point stack
+        let mut portal_buffers = PortalBuffers::new();
+        let mut ap_socket_set_entries: [SocketStorage; 3] = Default::default();
+        let ap_iface = create_interface(&mut ap_device);
+        let mut ap_stack = Stack::new(
+            ap_iface,
+            ap_device,
+            SocketSet::new(&mut ap_socket_set_entries[..]),
+            now,
+            rng.random(),
+        );
+        configure_ap_stack(&mut ap_stack);
+        let mut portal = CaptivePortal::new(&ap_stack, &mut portal_buffers);
+        // The window opens at boot, tries BLE and then the access point, and
+        // afterwards the radio stays off until the button is pressed
+        loop {
+            if pairing::init_advertising(
+                hci,
+                controller,
+                stack,
+                coap_client,
+                identity,
+                rng,
+                PairingMode::Pairing,
+                pairing_window_ms,
+                advertising_interval_ms,
+            ) {
+                break;
+            }
+            println!("BLE pairing timed out, starting captive portal");
+            if portal.run(controller, stack, coap_client, identity, pairing_window_ms) {
+                break;
+            }
+            println!("Pairing window closed, press the button to open it again");
+            wait_for_button_press();
+        }
+    }
+}
+
+fn wait_for_button_press() {
+    let delay = Delay::new();
+    // The radio is off, so is the LED
+    status_led::set(Status::Idle);
+    button::clear_events();
+    // Presses are only reported on release, so a held button isn't taken as an Improv authorization
+    while !matches!(
+        button::take_event(),
+        Some(ButtonEvent::ShortPress | ButtonEvent::DoublePress | ButtonEvent::LongPress)
+    ) {
+        delay.delay_millis(BUTTON_POLL_INTERVAL_MS);
     }
 }
 
@@ -0,0 +1,45 @@
+#!/usr/bin/env python3
+# Writes the brightness calibration of a board, read by src/brightness.rs
+# Usage: calibration.py <linear|gamma|cie1931> <min_output> <max_output> [gamma]
+#        calibration.py table <min_output> <max_output> <17 comma separated levels>
+# Table levels are shares of the output range from 0 to 65535, measured at brightness
+# spread evenly from 0 to 255
+import os
+import struct
+import subprocess
+import sys
+
+CALIBRATION_ADDR = 0xB000
+CURVES = {"linear": 0, "gamma": 1, "cie1931": 2, "table": 3}
+TABLE_POINTS = 17
+# Header and table, rounded up to whole words
+CALIBRATION_SIZE = 48
+USAGE = (
+    "Usage: calibration.py <linear|gamma|cie1931> <min_output> <max_output> [gamma]\n"
+    "       calibration.py table <min_output> <max_output> <17 comma separated levels>"
+)
+
+if len(sys.argv) not in (4, 5) or sys.argv[1] not in CURVES:
+    sys.exit(USAGE)
+
+curve = CURVES[sys.argv[1]]
+min_output = int(sys.argv[2])
+max_output = int(sys.argv[3])
+gamma = 1.0
+points = [0] * TABLE_POINTS
+if sys.argv[1] == "table":
+    if len(sys.argv) != 5:
+        sys.exit(USAGE)
+    points = [int(level) for level in sys.argv[4].split(",")]
+    if len(points) != TABLE_POINTS or not all(0 <= level <= 0xFFFF for level in points):
+        sys.exit("The table needs 17 levels from 0 to 65535")
+elif len(sys.argv) == 5:
+    gamma = float(sys.argv[4])
+
+record = bytes([0xCA, 0x1B, curve, 0]) + struct.pack("<fHH", gamma, min_output, max_output)
+record += struct.pack("<%dH" % TABLE_POINTS, *points)
+record = record.ljust(CALIBRATION_SIZE, b"\xff")
+path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "calibration.bin")
+with open(path, "wb") as f:
+    f.write(record)
+subprocess.run(["esptool.py", "write_flash", hex(CALIBRATION_ADDR), path], check=True)
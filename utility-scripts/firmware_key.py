@@ -0,0 +1,25 @@
+#!/usr/bin/env python3
+# Creates the Ed25519 key firmware images are signed with, keep it out of the repo
+# Prints the public key to build the firmware with as FIRMWARE_PUBLIC_KEY
+# Usage: firmware_key.py <key file>
+import os
+import sys
+
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+from cryptography.hazmat.primitives.serialization import (
+    Encoding,
+    NoEncryption,
+    PrivateFormat,
+    PublicFormat,
+)
+
+if len(sys.argv) != 2:
+    sys.exit("Usage: firmware_key.py <key file>")
+if os.path.exists(sys.argv[1]):
+    sys.exit(f"{sys.argv[1]} already exists, devices only trust the key they were built with")
+
+key = Ed25519PrivateKey.generate()
+with open(sys.argv[1], "wb", opener=lambda path, flags: os.open(path, flags, 0o600)) as f:
+    f.write(key.private_bytes(Encoding.PEM, PrivateFormat.PKCS8, NoEncryption()))
+public_key = key.public_key().public_bytes(Encoding.Raw, PublicFormat.Raw)
+print(f"FIRMWARE_PUBLIC_KEY={public_key.hex()}")
@@ -0,0 +1,44 @@
+#!/usr/bin/env python3
+# Turns a release build into a signed OTA image and the manifest the server offers it with, read by src/ota.rs
+# Build with the same SECURITY_VERSION and with FIRMWARE_PUBLIC_KEY from firmware_key.py
+# Usage: ota_image.py <elf> <server path> <signing key>
+import hashlib
+import json
+import os
+import struct
+import subprocess
+import sys
+import tomllib
+
+from cryptography.hazmat.primitives.serialization import load_pem_private_key
+
+if len(sys.argv) != 4:
+    sys.exit("Usage: ota_image.py <elf> <server path> <signing key>")
+
+elf = sys.argv[1]
+server_path = sys.argv[2]
+with open(sys.argv[3], "rb") as f:
+    signing_key = load_pem_private_key(f.read(), password=None)
+security_version = int(os.environ.get("SECURITY_VERSION", "0"))
+root = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+with open(os.path.join(root, "Cargo.toml"), "rb") as f:
+    version = tomllib.load(f)["package"]["version"]
+
+image_path = elf + ".bin"
+subprocess.run(["espflash", "save-image", "--chip", "esp32", elf, image_path], check=True)
+with open(image_path, "rb") as f:
+    image = f.read()
+
+digest = hashlib.sha256(image).digest()
+signature = signing_key.sign(digest + struct.pack("<I", security_version))
+manifest = {
+    "version": version,
+    "path": server_path,
+    "size": len(image),
+    "sha256": digest.hex(),
+    "security_version": security_version,
+    "signature": signature.hex(),
+}
+with open(image_path + ".json", "w") as f:
+    json.dump(manifest, f, indent=2)
+print(json.dumps(manifest, indent=2))